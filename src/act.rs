@@ -1,19 +1,24 @@
 use async_channel::Receiver;
 use async_std::{prelude::StreamExt, task::spawn};
 use serde::{de, Deserialize, Deserializer, Serialize};
-use std::{convert::TryFrom, error::Error, fmt::Display, str::FromStr};
+use serde_json::Value;
+use std::{fmt::Display, str::FromStr};
+use tracing::warn;
 use twilight_http::{request::AuditLogReason, Client as HttpClient};
 use twilight_model::id::{ChannelId, GuildId, RoleId, UserId};
 
-use crate::error;
+use crate::error::Error;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum Act {
 	CreateMessage {
+		#[serde(default)]
 		content: String,
 		#[serde(default, deserialize_with = "maybe_str_opt")]
 		channel_id: Option<u64>,
+		#[serde(default, skip_serializing_if = "Vec::is_empty")]
+		embeds: Vec<Value>,
 	},
 	AssignRole {
 		#[serde(deserialize_with = "maybe_str")]
@@ -35,6 +40,46 @@ pub enum Act {
 	},
 }
 
+/// A JSON response body from the target: either an act, or a message to reply with.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Reply {
+	Act(Act),
+	Message(BackendResponse),
+}
+
+/// A message to send back to the channel the event came from.
+///
+/// Unknown fields are rejected, so that a misspelt act isn't taken for an empty message.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendResponse {
+	pub content: Option<String>,
+	pub embeds: Option<Vec<Value>>,
+}
+
+impl Reply {
+	/// The act to perform, or `None` for an empty message.
+	pub fn into_act(self) -> Option<Act> {
+		match self {
+			Self::Act(act) => Some(act),
+			Self::Message(BackendResponse { content, embeds }) => {
+				let content = content.unwrap_or_default();
+				let embeds = embeds.unwrap_or_default();
+				if content.is_empty() && embeds.is_empty() {
+					None
+				} else {
+					Some(Act::CreateMessage {
+						content,
+						channel_id: None,
+						embeds,
+					})
+				}
+			}
+		}
+	}
+}
+
 #[derive(Deserialize)]
 #[serde(untagged)]
 enum MaybeStr {
@@ -51,7 +96,7 @@ where
 	let s = MaybeStr::deserialize(deserializer)?;
 	match s {
 		MaybeStr::S(s) => T::from_str(&s).map_err(de::Error::custom),
-		MaybeStr::N(n) => T::try_from(n).map_err(de::Error::custom),
+		MaybeStr::N(n) => Ok(T::from(n)),
 	}
 }
 
@@ -64,7 +109,7 @@ where
 	let s = MaybeStr::deserialize(deserializer)?;
 	Some(match s {
 		MaybeStr::S(s) => T::from_str(&s).map_err(de::Error::custom),
-		MaybeStr::N(n) => T::try_from(n).map_err(de::Error::custom),
+		MaybeStr::N(n) => Ok(T::from(n)),
 	})
 	.transpose()
 }
@@ -77,10 +122,7 @@ pub struct Stage {
 	pub default_channel_id: Option<ChannelId>,
 }
 
-pub async fn play_to_discord(
-	http: HttpClient,
-	mut feed: Receiver<Stage>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+pub async fn play_to_discord(http: HttpClient, mut feed: Receiver<Stage>) -> Result<(), Error> {
 	while let Some(stage) = feed.next().await {
 		spawn(send_to_discord(http.clone(), stage));
 	}
@@ -88,10 +130,7 @@ pub async fn play_to_discord(
 	Ok(())
 }
 
-async fn send_to_discord(
-	http: HttpClient,
-	stage: Stage,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+async fn send_to_discord(http: HttpClient, stage: Stage) -> Result<(), Error> {
 	let Stage {
 		act,
 		default_server_id,
@@ -102,12 +141,30 @@ async fn send_to_discord(
 		Act::CreateMessage {
 			content,
 			channel_id,
+			embeds,
 		} => {
 			let channel_id = channel_id
 				.map(ChannelId)
 				.or(default_channel_id)
-				.ok_or(error::MissingChannel)?;
-			http.create_message(channel_id).content(content)?.await?;
+				.ok_or(Error::MissingChannel)?;
+
+			let mut create = http.create_message(channel_id);
+			if !content.is_empty() {
+				create = create.content(content)?;
+			}
+
+			let mut embeds = embeds.into_iter();
+			if let Some(embed) = embeds.next() {
+				create = create.embed(serde_json::from_value(embed)?)?;
+			}
+			if embeds.len() > 0 {
+				warn!(
+					"only one embed per message is supported, dropping {} more",
+					embeds.len()
+				);
+			}
+
+			create.await?;
 		}
 		Act::AssignRole {
 			role_id,
@@ -118,7 +175,7 @@ async fn send_to_discord(
 			let server_id = server_id
 				.map(GuildId)
 				.or(default_server_id)
-				.ok_or(error::MissingServer)?;
+				.ok_or(Error::MissingServer)?;
 
 			let mut add = http.add_role(server_id, UserId(user_id), RoleId(role_id));
 
@@ -137,7 +194,7 @@ async fn send_to_discord(
 			let server_id = server_id
 				.map(GuildId)
 				.or(default_server_id)
-				.ok_or(error::MissingServer)?;
+				.ok_or(Error::MissingServer)?;
 
 			let mut rm = http.remove_guild_member_role(server_id, UserId(user_id), RoleId(role_id));
 
@@ -151,3 +208,26 @@ async fn send_to_discord(
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reply_message() {
+		let reply: Reply = serde_json::from_str(r#"{"content":"hi"}"#).unwrap();
+		assert!(matches!(
+			reply.into_act(),
+			Some(Act::CreateMessage { ref content, .. }) if content == "hi"
+		));
+
+		let reply: Reply = serde_json::from_str("{}").unwrap();
+		assert!(reply.into_act().is_none());
+	}
+
+	#[test]
+	fn reply_rejects_unknown_fields() {
+		assert!(serde_json::from_str::<Reply>(r#"{"contents":"hi"}"#).is_err());
+		assert!(serde_json::from_str::<Reply>(r#"{"create-mesage":{"content":"hi"}}"#).is_err());
+	}
+}
@@ -1,6 +1,7 @@
 use accord::{
 	act::Stage,
-	forward,
+	cache::LruInMemoryCache,
+	config, forward,
 	raccord::{Client, Sendable},
 	reverse,
 };
@@ -10,13 +11,14 @@ use async_std::{
 	task::spawn,
 };
 use serde::Serialize;
-use std::{env, error::Error, sync::Arc};
+use std::{env, sync::Arc};
 use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter, FmtSubscriber};
 use twilight_cache_inmemory::InMemoryCache;
 use twilight_gateway::Event;
+use twilight_http::Client as HttpClient;
 
 #[async_std::main]
-async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+async fn main() -> Result<(), accord::Error> {
 	tracing_log::LogTracer::init()?;
 	let rustlog = env::var("RUST_LOG").unwrap_or(String::from("info,accord=trace"));
 	if rustlog.split(',').any(|p| p == "pretty") {
@@ -34,11 +36,12 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 		tracing::subscriber::set_global_default(subscriber)?;
 	};
 
-	let bind = env::var("ACCORD_BIND").unwrap_or_else(|_| String::from("localhost:8181"));
-	let target_base = env::var("ACCORD_TARGET").expect("FATAL: missing env: ACCORD_TARGET");
-	let command_match = env::var("ACCORD_COMMAND_MATCH").ok();
-	let command_parse = env::var("ACCORD_COMMAND_PARSE").ok();
-	let target = Arc::new(Client::new(target_base, command_match, command_parse));
+	let config = config::load()?;
+	let bind = config
+		.bind
+		.clone()
+		.unwrap_or_else(|| String::from("localhost:8181"));
+	let target = Arc::new(Client::from_config(&config));
 
 	let (act_s, act_r) = unbounded();
 	let (ghost_s, ghost_r) = unbounded();
@@ -46,7 +49,7 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 	// TODO: sort out that match into something a little less of a mess
 	match play_to_target(target.clone(), act_r)
 		.join(reverse::server(bind, ghost_s))
-		.join(false_forward(target, ghost_r, act_s))
+		.join(false_forward(target, config.token, ghost_r, act_s))
 		.await
 	{
 		((Ok(_), Ok(_)), Ok(_)) => Ok(()),
@@ -65,15 +68,19 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
 async fn false_forward(
 	target: Arc<Client>,
+	token: Option<String>,
 	mut events: Receiver<(u64, Event)>,
 	player: Sender<Stage>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-	let cache = InMemoryCache::builder().build();
+) -> Result<(), accord::Error> {
+	let cache = LruInMemoryCache::new(InMemoryCache::builder().build(), None);
+	// only used to resolve mentions, with ACCORD_RESOLVE_MENTIONS_HTTP_FALLBACK
+	let http = HttpClient::new(token.unwrap_or_default());
 
 	while let Some((shard_id, event)) = events.next().await {
 		spawn(forward::handle_event(
 			cache.clone(),
 			target.clone(),
+			http.clone(),
 			shard_id,
 			event,
 			player.clone(),
@@ -86,7 +93,7 @@ async fn false_forward(
 async fn play_to_target(
 	target: Arc<Client>,
 	mut feed: Receiver<Stage>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+) -> Result<(), accord::Error> {
 	while let Some(stage) = feed.next().await {
 		target.post(Wrap(stage))?.await?;
 	}
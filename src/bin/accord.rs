@@ -1,11 +1,11 @@
-use accord::{act, raccord, reverse, Forward};
+use accord::{act, config, raccord, reverse, Forward};
 use async_channel::unbounded;
 use async_std::prelude::FutureExt;
-use std::{env, error::Error, sync::Arc};
+use std::{env, sync::Arc};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
 #[async_std::main]
-async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+async fn main() -> Result<(), accord::Error> {
 	tracing_log::LogTracer::init()?;
 	let subscriber = FmtSubscriber::builder()
 		.with_env_filter(EnvFilter::new(
@@ -14,38 +14,49 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 		.finish();
 	tracing::subscriber::set_global_default(subscriber)?;
 
-	let bind = env::var("ACCORD_BIND").unwrap_or_else(|_| String::from("localhost:8181"));
-	let token = env::var("DISCORD_TOKEN").expect("FATAL: missing env: DISCORD_TOKEN");
-	let target_base = env::var("ACCORD_TARGET").expect("FATAL: missing env: ACCORD_TARGET");
-	let command_match = env::var("ACCORD_COMMAND_MATCH").ok();
-	let command_parse = env::var("ACCORD_COMMAND_PARSE").ok();
-	let target = Arc::new(raccord::Client::new(
-		target_base,
-		command_match,
-		command_parse,
-	));
+	let config = config::load()?;
+	let bind = config
+		.bind
+		.clone()
+		.unwrap_or_else(|| String::from("localhost:8181"));
+	let target = Arc::new(raccord::Client::from_config(&config));
 
-	let fwd = Forward::init(token, target.clone()).await?;
+	#[cfg(feature = "metrics")]
+	{
+		accord::metrics::init(&config);
+		let port = config
+			.var("ACCORD_METRICS_PORT")
+			.unwrap_or_else(|| String::from("9090"));
+		async_std::task::spawn(async move {
+			if let Err(err) = accord::metrics::server(format!("0.0.0.0:{}", port)).await {
+				tracing::error!("metrics server failed: {}", err);
+			}
+		});
+	}
+
+	let fwd = Forward::init(&config, target.clone()).await?;
 
 	let (act_s, act_r) = unbounded();
 	let (ghost_s, ghost_r) = unbounded();
 
-	// TODO: sort out that match into something a little less of a mess
-	match act::play_to_discord(fwd.http.clone(), act_r)
-		.join(reverse::server(bind, ghost_s))
-		.join(fwd.worker(target, ghost_r, act_s))
-		.await
-	{
-		((Ok(_), Ok(_)), Ok(_)) => Ok(()),
-		((Ok(_), Ok(_)), Err(e)) | ((Ok(_), Err(e)), Ok(_)) | ((Err(e), Ok(_)), Ok(_)) => Err(e),
-		((Ok(_), Err(f)), Err(g)) | ((Err(f), Ok(_)), Err(g)) | ((Err(f), Err(g)), Ok(_)) => {
-			eprintln!("{}", f);
-			Err(g)
-		}
-		((Err(e), Err(f)), Err(g)) => {
-			eprintln!("{}", e);
-			eprintln!("{}", f);
-			Err(g)
-		}
-	}
+	let (stop_s, stop_r) = unbounded();
+	ctrlc::set_handler(move || {
+		stop_s.try_send(()).ok();
+	})?;
+	let shutdown = fwd.shutdown(target.clone());
+
+	// any part failing (e.g. the gateway rejecting our intents) takes the whole bot down,
+	// while SIGINT/SIGTERM shut it down gracefully (acts keep being played in the meantime)
+	let bot = act::play_to_discord(fwd.http.clone(), act_r)
+		.try_join(reverse::server(bind, ghost_s))
+		.try_join(fwd.worker(target, ghost_r, act_s));
+	let stop = async move {
+		stop_r.recv().await.ok();
+		shutdown.run().await;
+		Ok(())
+	};
+
+	async { bot.await.map(drop) }.race(stop).await?;
+
+	Ok(())
 }
@@ -0,0 +1,101 @@
+//! The twilight cache, optionally bounded to a number of guilds.
+
+use std::{
+	collections::HashMap,
+	ops::Deref,
+	sync::{Arc, Mutex},
+};
+use tracing::debug;
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_gateway::Event;
+use twilight_model::{gateway::payload::GuildDelete, id::GuildId};
+
+use crate::forward::event_guild_id;
+
+/// An [`InMemoryCache`] which holds at most `limit` guilds, from `ACCORD_CACHE_GUILD_LIMIT`.
+///
+/// Guilds are ranked by the last event seen from them, and when a new guild would go over the
+/// limit, the least recently active one is evicted (as if the bot had left it). An evicted guild
+/// comes back into the cache as events from it are seen again, but only partially until its next
+/// `GuildCreate`.
+#[derive(Clone, Debug)]
+pub struct LruInMemoryCache {
+	cache: InMemoryCache,
+	limit: Option<usize>,
+	activity: Arc<Mutex<Activity>>,
+}
+
+#[derive(Debug, Default)]
+struct Activity {
+	tick: u64,
+	last_seen: HashMap<GuildId, u64>,
+}
+
+impl LruInMemoryCache {
+	pub fn new(cache: InMemoryCache, limit: Option<usize>) -> Self {
+		Self {
+			cache,
+			limit,
+			activity: Arc::new(Mutex::new(Activity::default())),
+		}
+	}
+
+	/// Updates the cache with an event, evicting the least recently active guild if needed.
+	pub fn update(&self, event: &Event) {
+		self.cache.update(event);
+
+		let limit = match self.limit {
+			Some(limit) => limit,
+			None => return,
+		};
+
+		let guild_id = match event {
+			Event::GuildCreate(guild) => Some(guild.id),
+			Event::GuildDelete(guild) => {
+				self.activity.lock().unwrap().last_seen.remove(&guild.id);
+				None
+			}
+			event => event_guild_id(event),
+		};
+
+		let guild_id = match guild_id {
+			Some(id) => id,
+			None => return,
+		};
+
+		let mut activity = self.activity.lock().unwrap();
+		activity.tick += 1;
+		let tick = activity.tick;
+		activity.last_seen.insert(guild_id, tick);
+
+		while activity.last_seen.len() > limit {
+			let oldest = activity
+				.last_seen
+				.iter()
+				.min_by_key(|(_, seen)| **seen)
+				.map(|(id, _)| *id);
+			let oldest = match oldest {
+				Some(id) => id,
+				None => break,
+			};
+
+			debug!(
+				guild = oldest.0,
+				"evicting least recently active guild from cache"
+			);
+			activity.last_seen.remove(&oldest);
+			self.cache.update(&GuildDelete {
+				id: oldest,
+				unavailable: false,
+			});
+		}
+	}
+}
+
+impl Deref for LruInMemoryCache {
+	type Target = InMemoryCache;
+
+	fn deref(&self) -> &Self::Target {
+		&self.cache
+	}
+}
@@ -0,0 +1,321 @@
+//! Optional TOML configuration file, as an alternative to environment variables.
+//!
+//! If `ACCORD_CONFIG` points to a file, each of its keys is the name of an environment variable,
+//! in lowercase and without the `ACCORD_` prefix (so `target` is `ACCORD_TARGET`, `token` is
+//! `DISCORD_TOKEN`). Settings from the file are used where the environment doesn't set them, so
+//! the environment always wins. The rest of Accord reads its configuration through [`Config`].
+//!
+//! Keys which aren't known settings are rejected, so that typos don't go unnoticed.
+
+use serde::Deserialize;
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	env, fs,
+	str::FromStr,
+};
+use toml::Value;
+use tracing::info;
+
+use crate::error::Error;
+
+/// Settings which can be set in the file through [`Config::other`], keyed as they are there.
+///
+/// Keep this in sync with the configuration table in the README.
+const SETTINGS: &[&str] = &[
+	"targets",
+	"channel_routes",
+	"command_prefix",
+	"circuit_breaker_threshold",
+	"circuit_breaker_reset_secs",
+	"forward_mentions_only",
+	"max_mentions",
+	"allow_guilds",
+	"deny_guilds",
+	"allow_channels",
+	"deny_channels",
+	"channel_allow_regex",
+	"serialize_nulls",
+	"request_members",
+	"forward_reactions_for_bots",
+	"target_pool_min_idle",
+	"target_pool_max_idle",
+	"target_pool_idle_timeout_secs",
+	"http_pool_size",
+	"command_strip_prefix",
+	"command_help_trigger",
+	"command_help_response",
+	"command_case_insensitive",
+	"target_basic_auth_user",
+	"target_basic_auth_pass",
+	"ignore_self_commands",
+	"dm_opt_in_required",
+	"dm_opt_in_command",
+	"ignore_bots",
+	"ignore_self",
+	"post_telemetry",
+	"embed_urls_only",
+	"embed_forwarding",
+	"forward_only_commands",
+	"forward_bot_commands_only",
+	"dead_letter_file",
+	"target_error_path",
+	"shard_count_report_path",
+	"cluster_resume_sessions",
+	"session_store_path",
+	"emit_heartbeat_events",
+	"forward_raw_gateway_events",
+	"redact_voice_tokens",
+	"attachment_extensions_allow",
+	"filter_attachments_only",
+	"rate_limit_rps",
+	"rate_limit_burst",
+	"batch_max",
+	"batch_interval_ms",
+	"batch_event_types",
+	"target_connect_timeout_ms",
+	"forward_channel_types",
+	"forward_typing",
+	"forward_only_text_channels",
+	"guild_metadata_refresh_interval_secs",
+	"guild_features_header",
+	"guild_discovery_only",
+	"guild_shard_routing",
+	"ignore_partial_messages",
+	"gateway_intents_privileged_warn",
+	"tls_skip_verify",
+	"startup_delay_secs",
+	"startup_delay_mode",
+	"target_ipv4_only",
+	"content_hash_algorithm",
+	"max_retries",
+	"max_retries_per_minute",
+	"retry_base_delay_ms",
+	"request_timeout_ms",
+	"shutdown_timeout_ms",
+	"health_check_interval_secs",
+	"auth_chain",
+	"hmac_secret",
+	"resolve_mentions",
+	"resolve_mentions_http_fallback",
+	"payload_version",
+	"metrics_port",
+	"metric_labels",
+	"cache_guild_limit",
+	"ignore_channels_without_cache",
+	"disable_cache",
+];
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+	/// `DISCORD_TOKEN`
+	pub token: Option<String>,
+	/// `ACCORD_TARGET`
+	pub target: Option<String>,
+	/// `ACCORD_BIND`
+	pub bind: Option<String>,
+	/// `ACCORD_COMMAND_MATCH`
+	pub command_match: Option<String>,
+	/// `ACCORD_COMMAND_PARSE`
+	pub command_parse: Option<String>,
+	/// Every other `ACCORD_*` setting, keyed as described in the module docs.
+	#[serde(flatten)]
+	pub other: BTreeMap<String, Value>,
+	/// Settings from the file, by environment variable name.
+	#[serde(skip)]
+	file: HashMap<String, String>,
+}
+
+/// Loads the configuration file from `ACCORD_CONFIG`, if set, and merges it with the environment.
+///
+/// The returned config has the final values of the named fields, whichever source they came from.
+pub fn load() -> Result<Config, Error> {
+	let mut file = HashMap::new();
+	if let Ok(path) = env::var("ACCORD_CONFIG") {
+		let config: Config = toml::from_str(&fs::read_to_string(&path)?)
+			.map_err(|err| Error::Config(format!("{}: {}", path, err)))?;
+		file = config.vars()?.into_iter().collect();
+		info!("loaded configuration from {}", path);
+	}
+
+	Ok(Config::with_file(file))
+}
+
+impl Config {
+	fn with_file(file: HashMap<String, String>) -> Self {
+		let mut config = Self {
+			file,
+			..Self::default()
+		};
+		config.token = config.var("DISCORD_TOKEN");
+		config.target = config.var("ACCORD_TARGET");
+		config.bind = config.var("ACCORD_BIND");
+		config.command_match = config.var("ACCORD_COMMAND_MATCH");
+		config.command_parse = config.var("ACCORD_COMMAND_PARSE");
+		config
+	}
+
+	/// A setting by its environment variable name, from the environment or else the file.
+	pub fn var(&self, name: &str) -> Option<String> {
+		env::var(name).ok().or_else(|| self.file.get(name).cloned())
+	}
+
+	pub fn flag(&self, name: &str) -> Option<bool> {
+		self.var(name).map(|s| match s.to_lowercase().as_str() {
+			"1" | "true" | "yes" | "on" => true,
+			"0" | "false" | "no" | "off" | "" => false,
+			_ => panic!("FATAL: bad flag: {}", name),
+		})
+	}
+
+	/// Parses a comma-separated list of IDs.
+	pub fn ids(&self, name: &str) -> Option<HashSet<u64>> {
+		self.var(name).map(|s| {
+			s.split(',')
+				.map(str::trim)
+				.filter(|id| !id.is_empty())
+				.map(|id| {
+					id.parse()
+						.unwrap_or_else(|_| panic!("FATAL: bad value: {}", name))
+				})
+				.collect()
+		})
+	}
+
+	pub fn parse<T: FromStr>(&self, name: &str) -> Option<T> {
+		self.var(name).map(|s| {
+			s.parse()
+				.unwrap_or_else(|_| panic!("FATAL: bad value: {}", name))
+		})
+	}
+
+	/// The settings in this config, as environment variable names and values.
+	fn vars(&self) -> Result<Vec<(String, String)>, Error> {
+		let named = vec![
+			("DISCORD_TOKEN", &self.token),
+			("ACCORD_TARGET", &self.target),
+			("ACCORD_BIND", &self.bind),
+			("ACCORD_COMMAND_MATCH", &self.command_match),
+			("ACCORD_COMMAND_PARSE", &self.command_parse),
+		];
+
+		let mut vars: Vec<(String, String)> = named
+			.into_iter()
+			.filter_map(|(name, value)| Some((name.into(), value.clone()?)))
+			.collect();
+
+		for (key, value) in &self.other {
+			if !SETTINGS.contains(&key.as_str()) {
+				return Err(Error::Config(format!("unknown setting: {}", key)));
+			}
+
+			let value = env_value(value).ok_or_else(|| {
+				Error::Config(format!(
+					"{} must be a string, number, boolean, or array of those",
+					key
+				))
+			})?;
+			vars.push((format!("ACCORD_{}", key.to_uppercase()), value));
+		}
+
+		Ok(vars)
+	}
+}
+
+/// Renders a TOML value the way the equivalent environment variable would be written.
+fn env_value(value: &Value) -> Option<String> {
+	Some(match value {
+		Value::String(s) => s.clone(),
+		Value::Integer(n) => n.to_string(),
+		Value::Float(n) => n.to_string(),
+		Value::Boolean(b) => String::from(if *b { "1" } else { "0" }),
+		Value::Array(items) => items
+			.iter()
+			.map(env_value)
+			.collect::<Option<Vec<_>>>()?
+			.join(","),
+		Value::Datetime(_) | Value::Table(_) => return None,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::BTreeSet;
+
+	#[test]
+	fn settings_match_readme() {
+		let documented: BTreeSet<String> = include_str!("../README.md")
+			.lines()
+			.filter_map(|line| {
+				let name = line
+					.strip_prefix("| `ACCORD_")
+					.or_else(|| line.strip_prefix("| [`ACCORD_"))?;
+				Some(name.split('`').next()?.to_lowercase())
+			})
+			.filter(|name| {
+				!["target", "bind", "command_match", "command_parse"].contains(&name.as_str())
+			})
+			.collect();
+		let known: BTreeSet<String> = SETTINGS.iter().map(|name| name.to_string()).collect();
+		assert_eq!(documented, known);
+	}
+
+	fn file(vars: &[(&str, &str)]) -> Config {
+		Config::with_file(
+			vars.iter()
+				.map(|(name, value)| (name.to_string(), value.to_string()))
+				.collect(),
+		)
+	}
+
+	#[test]
+	fn ids_parses_lists() {
+		let config = file(&[
+			("ACCORD_TEST_IDS", " 1, 2,,3 "),
+			("ACCORD_TEST_IDS_EMPTY", ""),
+		]);
+		assert_eq!(
+			config.ids("ACCORD_TEST_IDS"),
+			Some(vec![1, 2, 3].into_iter().collect())
+		);
+		assert_eq!(config.ids("ACCORD_TEST_IDS_EMPTY"), Some(HashSet::new()));
+		assert_eq!(config.ids("ACCORD_TEST_IDS_UNSET"), None);
+	}
+
+	#[test]
+	#[should_panic(expected = "FATAL: bad value: ACCORD_TEST_IDS_BAD")]
+	fn ids_rejects_garbage() {
+		file(&[("ACCORD_TEST_IDS_BAD", "1,two")]).ids("ACCORD_TEST_IDS_BAD");
+	}
+
+	#[test]
+	fn file_settings_are_read_without_the_environment() {
+		let config = file(&[
+			("ACCORD_TARGET", "http://localhost"),
+			("ACCORD_TEST_FILE_FLAG", "1"),
+		]);
+		assert_eq!(config.target.as_deref(), Some("http://localhost"));
+		assert_eq!(config.flag("ACCORD_TEST_FILE_FLAG"), Some(true));
+		assert_eq!(env::var_os("ACCORD_TEST_FILE_FLAG"), None);
+	}
+
+	#[test]
+	fn unknown_settings_are_rejected() {
+		let config: Config =
+			toml::from_str("target = \"http://localhost\"\nmax_retries = 2").unwrap();
+		assert_eq!(
+			config.vars().unwrap(),
+			vec![
+				(
+					String::from("ACCORD_TARGET"),
+					String::from("http://localhost")
+				),
+				(String::from("ACCORD_MAX_RETRIES"), String::from("2")),
+			]
+		);
+
+		let config: Config = toml::from_str("max_retires = 2").unwrap();
+		assert!(config.vars().is_err());
+	}
+}
@@ -1,9 +1,126 @@
-use thiserror::Error;
+use twilight_gateway::cluster::ClusterStartError;
+use twilight_http::request::{
+	channel::message::create_message::CreateMessageError, AuditLogReasonError,
+};
 
-#[derive(Copy, Clone, Debug, Error)]
-#[error("no server information available")]
-pub struct MissingServer;
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("configuration error: {0}")]
+	Config(String),
 
-#[derive(Copy, Clone, Debug, Error)]
-#[error("no channel information available")]
-pub struct MissingChannel;
+	#[error("failed to start gateway cluster: {0}")]
+	Gateway(Box<ClusterStartError>),
+
+	#[error("gateway rejected the requested intents (close code {0})")]
+	IntentsRejected(u16),
+
+	#[error("request to target failed: {0}")]
+	Http(isahc::Error),
+
+	#[error("request to target timed out")]
+	Timeout,
+
+	#[error("circuit breaker open, request to target discarded")]
+	CircuitOpen,
+
+	#[error("client is draining, request to target discarded")]
+	Draining,
+
+	#[error("discord api error: {0}")]
+	Discord(Box<twilight_http::Error>),
+
+	#[error("invalid discord request: {0}")]
+	CreateMessage(#[from] CreateMessageError),
+
+	#[error("invalid audit log reason: {0}")]
+	AuditLogReason(#[from] AuditLogReasonError),
+
+	#[error("no server information available")]
+	MissingServer,
+
+	#[error("no channel information available")]
+	MissingChannel,
+
+	#[error("invalid request: {0}")]
+	Request(#[from] isahc::http::Error),
+
+	#[error("invalid json: {0}")]
+	Json(#[from] serde_json::Error),
+
+	#[error("i/o error: {0}")]
+	Io(#[from] std::io::Error),
+
+	#[error("internal channel closed")]
+	ChannelClosed,
+
+	#[error("failed to set up logging: {0}")]
+	Logging(String),
+
+	#[error("failed to set up signal handler: {0}")]
+	Signal(#[from] ctrlc::Error),
+}
+
+impl Error {
+	/// A short, stable name for the kind of error, for reporting to the target.
+	pub fn kind(&self) -> &'static str {
+		match self {
+			Self::Config(_) => "config",
+			Self::Gateway(_) => "gateway",
+			Self::IntentsRejected(_) => "intents_rejected",
+			Self::Http(_) => "http",
+			Self::Timeout => "timeout",
+			Self::CircuitOpen => "circuit_open",
+			Self::Draining => "draining",
+			Self::Discord(_) => "discord",
+			Self::CreateMessage(_) => "create_message",
+			Self::AuditLogReason(_) => "audit_log_reason",
+			Self::MissingServer => "missing_server",
+			Self::MissingChannel => "missing_channel",
+			Self::Request(_) => "request",
+			Self::Json(_) => "json",
+			Self::Io(_) => "io",
+			Self::ChannelClosed => "channel_closed",
+			Self::Logging(_) => "logging",
+			Self::Signal(_) => "signal",
+		}
+	}
+}
+
+impl From<ClusterStartError> for Error {
+	fn from(err: ClusterStartError) -> Self {
+		Self::Gateway(Box::new(err))
+	}
+}
+
+impl From<twilight_http::Error> for Error {
+	fn from(err: twilight_http::Error) -> Self {
+		Self::Discord(Box::new(err))
+	}
+}
+
+impl From<isahc::Error> for Error {
+	fn from(err: isahc::Error) -> Self {
+		match err {
+			isahc::Error::Timeout => Self::Timeout,
+			err => Self::Http(err),
+		}
+	}
+}
+
+impl From<tracing_log::log_tracer::SetLoggerError> for Error {
+	fn from(err: tracing_log::log_tracer::SetLoggerError) -> Self {
+		Self::Logging(err.to_string())
+	}
+}
+
+impl From<tracing::subscriber::SetGlobalDefaultError> for Error {
+	fn from(err: tracing::subscriber::SetGlobalDefaultError) -> Self {
+		Self::Logging(err.to_string())
+	}
+}
+
+impl<T> From<async_channel::SendError<T>> for Error {
+	fn from(_: async_channel::SendError<T>) -> Self {
+		Self::ChannelClosed
+	}
+}
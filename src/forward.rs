@@ -1,15 +1,34 @@
 use async_channel::{Receiver, Sender};
-use async_std::{prelude::StreamExt, task::spawn};
+use async_std::{
+	fs,
+	future::timeout,
+	path::{Path, PathBuf},
+	prelude::StreamExt,
+	task::{sleep, spawn},
+};
 use futures::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use isahc::{http::Response, ResponseExt};
-use std::{error::Error, fmt::Debug, io::Read, str::FromStr, sync::Arc};
+use std::{
+	collections::HashMap,
+	convert::TryFrom,
+	fmt::Debug,
+	io::{ErrorKind, Read},
+	str::FromStr,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use tracing::{debug, error, info, trace, warn};
 use twilight_cache_inmemory::{EventType, InMemoryCache};
-use twilight_gateway::{cluster::Cluster, Event};
+use twilight_gateway::{cluster::Cluster, shard::ResumeSession, Event};
 use twilight_http::Client as HttpClient;
 use twilight_model::{
+	channel::{Channel, ChannelType, Message},
 	gateway::{
-		payload::update_status::UpdateStatusInfo,
+		event::shard::Disconnected,
+		payload::{update_status::UpdateStatusInfo, RequestGuildMembers},
 		presence::{Activity, ActivityType, Status},
 		Intents,
 	},
@@ -17,21 +36,36 @@ use twilight_model::{
 };
 
 use crate::{
-	act::{Act, Stage},
-	raccord,
+	act::{Act, Reply, Stage},
+	cache::LruInMemoryCache,
+	config::Config,
+	error::Error,
+	raccord::{self, InFlight},
 };
 
 pub struct Forward {
-	pub cache: InMemoryCache,
+	pub cache: LruInMemoryCache,
 	pub cluster: Cluster,
 	pub http: HttpClient,
+	pub request_members: bool,
+	pub post_telemetry: bool,
+	pub started_at: SystemTime,
+	pub session_store: Option<PathBuf>,
+	pub emit_heartbeats: bool,
+	pub startup_delay: Duration,
+	pub startup_queue: bool,
+	pub shutdown_timeout: Duration,
+	/// Events being handled, for [`Shutdown`].
+	pub pending: Arc<AtomicUsize>,
 }
 
 impl Forward {
-	pub async fn init(
-		token: String,
-		target: Arc<raccord::Client>,
-	) -> Result<Self, Box<dyn Error + Send + Sync>> {
+	pub async fn init(config: &Config, target: Arc<raccord::Client>) -> Result<Self, Error> {
+		let token = config
+			.token
+			.clone()
+			.ok_or_else(|| Error::Config(String::from("missing DISCORD_TOKEN")))?;
+		let started_at = SystemTime::now();
 		let mut update_status = None;
 		if let Ok(mut connecting_res) = target.get(raccord::Connecting)?.await {
 			if connecting_res.status().is_success() {
@@ -90,27 +124,118 @@ impl Forward {
 		}
 
 		// TODO: env var control for intents (notably for privileged intents)
-		let mut config = Cluster::builder(
-			&token,
-			Intents::DIRECT_MESSAGES | Intents::GUILD_MESSAGES | Intents::GUILD_MEMBERS,
-		);
+		let mut intents = Intents::GUILDS
+			| Intents::DIRECT_MESSAGES
+			| Intents::GUILD_MESSAGES
+			| Intents::GUILD_MEMBERS
+			| Intents::GUILD_BANS
+			| Intents::GUILD_MESSAGE_REACTIONS
+			| Intents::DIRECT_MESSAGE_REACTIONS;
+
+		// typing events are very high volume, so they're only subscribed to on request
+		if config.flag("ACCORD_FORWARD_TYPING").unwrap_or(false) {
+			intents |= Intents::GUILD_MESSAGE_TYPING | Intents::DIRECT_MESSAGE_TYPING;
+		}
+
+		if config
+			.flag("ACCORD_GATEWAY_INTENTS_PRIVILEGED_WARN")
+			.unwrap_or(false)
+		{
+			// twilight doesn't know about MESSAGE_CONTENT yet, so it can't be requested here
+			let privileged = intents & (Intents::GUILD_MEMBERS | Intents::GUILD_PRESENCES);
+			if !privileged.is_empty() {
+				warn!(
+					"privileged intents requested ({:?}), these must be enabled for the bot in the Discord Developer Portal",
+					privileged
+				);
+			}
+		}
+
+		// no compression option here: twilight always connects with `compress=zlib-stream`
+		let mut builder = Cluster::builder(&token, intents);
 
 		if let Some(presence) = update_status {
-			config = config.presence(presence);
+			builder = builder.presence(presence);
 		}
 
-		let cluster = config.build().await?;
+		let session_store = if config
+			.flag("ACCORD_CLUSTER_RESUME_SESSIONS")
+			.unwrap_or(false)
+		{
+			Some(PathBuf::from(
+				config
+					.var("ACCORD_SESSION_STORE_PATH")
+					.unwrap_or_else(|| String::from("accord-sessions.json")),
+			))
+		} else {
+			None
+		};
+
+		if let Some(ref path) = session_store {
+			match fs::read(path).await {
+				Ok(json) => match serde_json::from_slice::<HashMap<u64, ResumeSession>>(&json) {
+					Ok(sessions) => {
+						info!("attempting to resume {} gateway sessions", sessions.len());
+						builder = builder.resume_sessions(sessions);
+					}
+					Err(err) => {
+						warn!(
+							"stored gateway sessions in {} are corrupt, starting fresh: {}",
+							path.display(),
+							err
+						);
+					}
+				},
+				Err(err) if err.kind() == ErrorKind::NotFound => {
+					debug!("no stored gateway sessions to resume");
+				}
+				Err(err) => return Err(err.into()),
+			}
+		}
+
+		let cluster = builder.build().await?;
 
 		let cluster_spawn = cluster.clone();
 		spawn(async move {
 			cluster_spawn.up().await;
 		});
 
+		let shard_count = cluster
+			.shards()
+			.first()
+			.map(|shard| shard.config().shard()[1])
+			.unwrap_or_default();
+		target.set_shard_count(shard_count);
+
+		if let Some(path) = config.var("ACCORD_SHARD_COUNT_REPORT_PATH") {
+			info!("reporting shard count ({}) to target", shard_count);
+			let res = match target.post(raccord::ShardCount { path, shard_count }) {
+				Ok(res) => res.await,
+				Err(err) => Err(err),
+			};
+			match res {
+				Ok(res) if !res.status().is_success() => {
+					warn!("target rejected shard count report: {:?}", res.status())
+				}
+				Ok(_) => {}
+				Err(err) => warn!("failed to report shard count: {}", err),
+			}
+		}
+
 		let http = HttpClient::new(&token);
 
+		let bot = http.current_user().await?;
+		debug!(id = bot.id.0, "bot user is {}", bot.name);
+		target.set_bot_id(bot.id.0);
+
 		let cache = InMemoryCache::builder()
 			.event_types(
-				EventType::MESSAGE_CREATE
+				EventType::GUILD_CREATE
+					| EventType::GUILD_DELETE
+					| EventType::CHANNEL_CREATE
+					| EventType::CHANNEL_DELETE
+					| EventType::CHANNEL_UPDATE
+					| EventType::MESSAGE_CREATE
 					| EventType::MESSAGE_DELETE
 					| EventType::MESSAGE_DELETE_BULK
 					| EventType::MESSAGE_UPDATE
@@ -120,11 +245,41 @@ impl Forward {
 					| EventType::MEMBER_REMOVE,
 			)
 			.build();
+		let cache = LruInMemoryCache::new(cache, config.parse("ACCORD_CACHE_GUILD_LIMIT"));
+
+		if !target.cache_enabled() {
+			// the cache is still passed around, but stays empty: every lookup misses, and
+			// cache-derived fields (role names, channel names...) are left out of payloads
+			info!("cache disabled, payloads will not be enriched with cached data");
+		}
+
+		let request_members = config.flag("ACCORD_REQUEST_MEMBERS").unwrap_or(false);
+		let post_telemetry = config.flag("ACCORD_POST_TELEMETRY").unwrap_or(false);
+		let emit_heartbeats = config.flag("ACCORD_EMIT_HEARTBEAT_EVENTS").unwrap_or(false);
+		let startup_delay =
+			Duration::from_secs(config.parse("ACCORD_STARTUP_DELAY_SECS").unwrap_or(0));
+		let startup_queue = match config.var("ACCORD_STARTUP_DELAY_MODE").as_deref() {
+			None | Some("drop") => false,
+			Some("queue") => true,
+			Some(_) => panic!("FATAL: bad value: ACCORD_STARTUP_DELAY_MODE"),
+		};
+
+		let shutdown_timeout =
+			Duration::from_millis(config.parse("ACCORD_SHUTDOWN_TIMEOUT_MS").unwrap_or(5000));
 
 		Ok(Self {
 			cache,
 			cluster,
 			http,
+			request_members,
+			post_telemetry,
+			started_at,
+			session_store,
+			emit_heartbeats,
+			startup_delay,
+			startup_queue,
+			shutdown_timeout,
+			pending: Arc::new(AtomicUsize::new(0)),
 		})
 	}
 
@@ -133,61 +288,425 @@ impl Forward {
 		target: Arc<raccord::Client>,
 		ghosts: Receiver<(u64, Event)>,
 		player: Sender<Stage>,
-	) -> Result<(), Box<dyn Error + Send + Sync>> {
+	) -> Result<(), Error> {
+		target.set_cache((*self.cache).clone());
+		spawn(target.clone().monitor_health());
+		spawn(target.clone().flush_batches());
+		spawn(target.clone().refresh_guild_metadata(self.http.clone()));
+
 		let solids = self.cluster.events();
 		let mut events = solids.merge(ghosts);
+		let mut telemetry_sent = false;
+
+		let delay_left = self
+			.startup_delay
+			.checked_sub(self.started_at.elapsed().unwrap_or_default());
+		if let (true, Some(wait)) = (self.startup_queue, delay_left) {
+			// events queue up in the cluster until we start reading them
+			info!("holding events for {:?} after startup", wait);
+			sleep(wait).await;
+		}
 
 		while let Some((shard_id, event)) = events.next().await {
-			spawn(handle_event(
+			if let (false, Event::Ready(ready)) = (telemetry_sent, &event) {
+				telemetry_sent = true;
+				let telemetry = raccord::Telemetry {
+					started_at: self
+						.started_at
+						.duration_since(UNIX_EPOCH)
+						.map(|d| d.as_secs())
+						.unwrap_or_default(),
+					bot_id: ready.user.id.0,
+					session_id: ready.session_id.clone(),
+					shard_count: ready
+						.shard
+						.map(|[_, total]| total)
+						.unwrap_or_else(|| self.cluster.shards().len() as u64),
+					intents: format!("{:?}", self.cluster.config().shard_config().intents()),
+					target: target.redacted_base(),
+				};
+
+				info!(
+					started_at = telemetry.started_at,
+					bot_id = telemetry.bot_id,
+					session_id = telemetry.session_id.as_str(),
+					shard_count = telemetry.shard_count,
+					intents = telemetry.intents.as_str(),
+					target = telemetry.target.as_str(),
+					"startup telemetry"
+				);
+
+				if self.post_telemetry {
+					let target = target.clone();
+					spawn(async move {
+						match target.post(telemetry) {
+							Ok(req) => {
+								if let Err(err) = req.await {
+									warn!("failed to post telemetry: {}", err);
+								}
+							}
+							Err(err) => warn!("failed to post telemetry: {}", err),
+						}
+					});
+				}
+			}
+
+			if let Event::ShardDisconnected(Disconnected {
+				code: Some(code @ 4013..=4014),
+				ref reason,
+				..
+			}) = event
+			{
+				error!(
+					shard_id,
+					code,
+					reason = ?reason,
+					"gateway rejected the requested intents, check they are enabled in the Discord Developer Portal"
+				);
+				return Err(Error::IntentsRejected(code));
+			}
+
+			if let (Some(path), Event::Ready(_)) = (&self.session_store, &event) {
+				if let Err(err) = self.store_sessions(path).await {
+					warn!("failed to store gateway sessions: {}", err);
+				}
+			}
+
+			if let (true, Event::GatewayHeartbeatAck) = (self.emit_heartbeats, &event) {
+				if let Some(info) = self.cluster.shard(shard_id).and_then(|s| s.info().ok()) {
+					let heartbeat = raccord::HeartbeatAck {
+						shard_id,
+						latency_ms: info.latency().recent().back().map(|d| d.as_millis() as u64),
+						sequence: info.seq(),
+					};
+
+					trace!(shard_id, latency_ms = ?heartbeat.latency_ms, "heartbeat ack");
+					let target = target.clone();
+					spawn(async move {
+						match target.post(heartbeat) {
+							Ok(req) => {
+								if let Err(err) = req.await {
+									warn!("failed to post heartbeat: {}", err);
+								}
+							}
+							Err(err) => warn!("failed to post heartbeat: {}", err),
+						}
+					});
+				}
+			}
+
+			if let (true, Event::GuildCreate(guild)) = (self.request_members, &event) {
+				debug!(guild = guild.id.0, "requesting guild members");
+				let request = RequestGuildMembers::builder(guild.id).query("", None);
+				if let Err(err) = self.cluster.command(shard_id, &request).await {
+					warn!("failed to request members for guild {}: {}", guild.id, err);
+				}
+			}
+
+			if !self.startup_queue
+				&& self.started_at.elapsed().unwrap_or_default() < self.startup_delay
+			{
+				trace!("dropping event received during startup delay");
+				if target.cache_enabled() {
+					self.cache.update(&event);
+				}
+				continue;
+			}
+
+			let pending = InFlight::new(&self.pending);
+			let (cache, target, http, player) = (
 				self.cache.clone(),
 				target.clone(),
-				shard_id,
-				event,
+				self.http.clone(),
 				player.clone(),
-			));
+			);
+			spawn(async move {
+				handle_event(cache, target, http, shard_id, event, player).await;
+				drop(pending);
+			});
 		}
 
 		Ok(())
 	}
+
+	/// A handle to shut down gracefully with, once [`worker`](Self::worker) has taken the forwarder.
+	pub fn shutdown(&self, target: Arc<raccord::Client>) -> Shutdown {
+		Shutdown {
+			cluster: self.cluster.clone(),
+			pending: self.pending.clone(),
+			session_store: self.session_store.clone(),
+			target,
+			timeout: self.shutdown_timeout,
+		}
+	}
+
+	async fn store_sessions(&self, path: &Path) -> Result<(), Error> {
+		let sessions: HashMap<u64, ResumeSession> = self
+			.cluster
+			.info()
+			.into_iter()
+			.filter_map(|(id, info)| {
+				info.session_id().map(|session_id| {
+					(
+						id,
+						ResumeSession {
+							session_id: session_id.to_string(),
+							sequence: info.seq(),
+						},
+					)
+				})
+			})
+			.collect();
+
+		write_sessions(path, &sessions).await
+	}
+}
+
+async fn write_sessions(path: &Path, sessions: &HashMap<u64, ResumeSession>) -> Result<(), Error> {
+	trace!("storing {} gateway sessions", sessions.len());
+	fs::write(path, serde_json::to_vec(sessions)?).await?;
+	Ok(())
+}
+
+/// Stops receiving events, then waits up to `ACCORD_SHUTDOWN_TIMEOUT_MS` for the ones already
+/// received to be handled, retries included.
+///
+/// With `ACCORD_CLUSTER_RESUME_SESSIONS`, the shards are brought down resumably and their sessions
+/// stored, so they can be resumed on the next start.
+pub struct Shutdown {
+	cluster: Cluster,
+	pending: Arc<AtomicUsize>,
+	session_store: Option<PathBuf>,
+	target: Arc<raccord::Client>,
+	timeout: Duration,
+}
+
+impl Shutdown {
+	pub async fn run(self) {
+		let deadline = Instant::now() + self.timeout;
+		info!(
+			"shutting down, waiting for {} events being handled",
+			self.pending.load(Ordering::SeqCst)
+		);
+		match self.session_store {
+			Some(ref path) => {
+				let sessions = self.cluster.down_resumable();
+				if let Err(err) = write_sessions(path, &sessions).await {
+					warn!("failed to store gateway sessions: {}", err);
+				}
+			}
+			None => self.cluster.down(),
+		}
+
+		while self.pending.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+			sleep(Duration::from_millis(50)).await;
+		}
+
+		let left = self.pending.load(Ordering::SeqCst);
+		if left > 0 {
+			warn!(
+				"shutdown timed out with {} events still being handled, they will be lost",
+				left
+			);
+			return;
+		}
+
+		let remaining = deadline.saturating_duration_since(Instant::now());
+		if timeout(remaining, self.target.drain()).await.is_err() {
+			warn!("shutdown timed out with requests to the target still in flight");
+		}
+	}
+}
+
+/// The guild an event comes from, for the events Accord forwards.
+pub(crate) fn event_guild_id(event: &Event) -> Option<GuildId> {
+	match *event {
+		Event::MessageCreate(ref message) => message.guild_id,
+		Event::MessageUpdate(ref update) => update.guild_id,
+		Event::MessageDelete(ref delete) => delete.guild_id,
+		Event::MessageDeleteBulk(ref delete) => delete.guild_id,
+		Event::ReactionAdd(ref reaction) => reaction.guild_id,
+		Event::ReactionRemove(ref reaction) => reaction.guild_id,
+		Event::TypingStart(ref typing) => typing.guild_id,
+		Event::MemberAdd(ref member) => Some(member.guild_id),
+		Event::MemberRemove(ref member) => Some(member.guild_id),
+		Event::MemberChunk(ref chunk) => Some(chunk.guild_id),
+		Event::ChannelCreate(ref create) => channel_guild_id(&create.0),
+		Event::ChannelUpdate(ref update) => channel_guild_id(&update.0),
+		Event::ChannelDelete(ref delete) => channel_guild_id(&delete.0),
+		Event::BanAdd(ref ban) => Some(ban.guild_id),
+		Event::BanRemove(ref ban) => Some(ban.guild_id),
+		Event::VoiceServerUpdate(ref update) => update.guild_id,
+		_ => None,
+	}
+}
+
+fn channel_guild_id(channel: &Channel) -> Option<GuildId> {
+	match channel {
+		Channel::Guild(channel) => channel.guild_id(),
+		Channel::Group(_) | Channel::Private(_) => None,
+	}
+}
+
+fn dm_channel_id(channel: &Channel) -> Option<ChannelId> {
+	match channel {
+		Channel::Group(_) | Channel::Private(_) => Some(channel.id()),
+		Channel::Guild(_) => None,
+	}
 }
 
 pub async fn handle_event(
-	cache: InMemoryCache,
+	cache: LruInMemoryCache,
 	target: Arc<raccord::Client>,
+	http: HttpClient,
 	shard_id: u64,
 	event: Event,
 	player: Sender<Stage>,
 ) {
-	if let Err(err) = try_event(cache, target, shard_id, event, player).await {
+	let kind = event.kind();
+	if let Err(err) = try_event(cache, target.clone(), http, shard_id, event, player).await {
 		error!("got error while handling event:\n{}", err);
+		let event_type = kind
+			.name()
+			.map_or_else(|| format!("{:?}", kind), String::from);
+		target.report_error(&err, &event_type).await;
 	}
 }
 
 pub async fn try_event(
-	cache: InMemoryCache,
+	cache: LruInMemoryCache,
 	target: Arc<raccord::Client>,
+	http: HttpClient,
 	shard_id: u64,
 	event: Event,
 	player: Sender<Stage>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-	trace!("updating twilight cache");
-	cache.update(&event);
+) -> Result<(), Error> {
+	if target.cache_enabled() {
+		trace!("updating twilight cache");
+		cache.update(&event);
+	}
+
+	let guild_id = event_guild_id(&event);
+	let channel_id = match event {
+		Event::MessageCreate(ref message) => Some(message.channel_id),
+		Event::MessageUpdate(ref update) => Some(update.channel_id),
+		Event::MessageDelete(ref delete) => Some(delete.channel_id),
+		Event::MessageDeleteBulk(ref delete) => Some(delete.channel_id),
+		Event::ReactionAdd(ref reaction) => Some(reaction.channel_id),
+		Event::ReactionRemove(ref reaction) => Some(reaction.channel_id),
+		Event::TypingStart(ref typing) => Some(typing.channel_id),
+		_ => None,
+	};
+	if !target.is_allowed(guild_id.map(|id| id.0), channel_id.map(|id| id.0)) {
+		return Ok(());
+	}
+
+	if channel_id.is_some_and(|id| !target.wants_text_channel(id, &cache)) {
+		return Ok(());
+	}
+
+	let dm_channel_id = match event {
+		Event::MessageCreate(_) => None,
+		Event::ChannelCreate(ref channel) => dm_channel_id(channel),
+		Event::ChannelUpdate(ref channel) => dm_channel_id(channel),
+		Event::ChannelDelete(ref channel) => dm_channel_id(channel),
+		_ if guild_id.is_none() => channel_id,
+		_ => None,
+	};
+	if let Some(id) = dm_channel_id {
+		let user_id = match event {
+			Event::MessageUpdate(ref update) => update.author.as_ref().map(|author| author.id),
+			Event::ReactionAdd(ref reaction) => Some(reaction.0.user_id),
+			Event::ReactionRemove(ref reaction) => Some(reaction.0.user_id),
+			Event::TypingStart(ref typing) => Some(typing.user_id),
+			Event::ChannelCreate(ref channel) => match channel.0 {
+				Channel::Private(ref dm) => dm.recipients.first().map(|user| user.id),
+				_ => None,
+			},
+			_ => None,
+		};
+		if !target.wants_dm_event(id.0, user_id.map(|id| id.0)) {
+			trace!("DM event is from a user who has not opted in, dropping");
+			return Ok(());
+		}
+	}
+
+	match event {
+		Event::GuildCreate(ref guild) => {
+			target.note_guild(guild);
+			target.note_guild_metadata(guild);
+		}
+		Event::GuildDelete(ref guild) if !guild.unavailable => {
+			target.forget_guild_metadata(guild.id);
+		}
+		_ => {}
+	}
+
+	if guild_id.is_some_and(|id| !target.wants_guild(id)) {
+		trace!("guild is not discoverable, skipping");
+		return Ok(());
+	}
+
+	match event {
+		Event::MessageCreate(ref message) if !target.wants_message(message, &cache) => {
+			return Ok(());
+		}
+		Event::MessageUpdate(ref update) if !target.wants_message_update(update, &cache) => {
+			return Ok(());
+		}
+		Event::MessageDelete(ref delete)
+			if !target.wants_message_delete(delete.guild_id, delete.channel_id, &cache) =>
+		{
+			return Ok(());
+		}
+		Event::MessageDeleteBulk(ref delete)
+			if !target.wants_message_delete(delete.guild_id, delete.channel_id, &cache) =>
+		{
+			return Ok(());
+		}
+		Event::ReactionAdd(ref reaction) if !target.wants_reaction(reaction, &cache) => {
+			return Ok(());
+		}
+		Event::ReactionRemove(ref reaction) if !target.wants_reaction(reaction, &cache) => {
+			return Ok(());
+		}
+		_ => {}
+	}
 
 	match event {
 		Event::MessageCreate(message) if message.guild_id.is_some() => {
 			debug!("received guild message create");
-			let msg = raccord::ServerMessage::from(&**message);
-			let res = if let Some(command) = target.parse_command(&msg.content) {
+			if target.ignores_author(&message.author) {
+				trace!("message author is ignored, dropping");
+				return Ok(());
+			}
+
+			let mut msg = raccord::ServerMessage::from(&**message);
+			msg.content_hash = target.hash_content(&msg.content);
+			msg.content_resolved = target.resolve_mentions(&message, &cache, &http).await;
+			msg.server = target.guild_metadata(msg.server_id);
+			msg.server_member_count = cache
+				.guild(GuildId(msg.server_id))
+				.and_then(|guild| guild.member_count)
+				.and_then(|count| u32::try_from(count).ok());
+			let mut help = None;
+			let res = if let Some(command) = parse_command(&target, &message) {
 				trace!("submitting act: {:?}", command);
-				target.post(raccord::Command {
-					command,
-					message: msg,
-				})
+				help = target.help_response(&command);
+				target.post(command.into_command(msg))
+			} else if target.forward_only_commands() {
+				trace!("message is not a command, dropping");
+				target.count_dropped_message();
+				return Ok(());
 			} else {
 				trace!("submitting act: {:?}", msg);
 				target.post(msg)
 			}?
 			.await?;
+			if reply_help(&res, help, &player, message.guild_id, message.channel_id).await? {
+				return Ok(());
+			}
+
 			trace!("handing off response: {:?}", res);
 			handle_response(
 				res,
@@ -200,21 +719,99 @@ pub async fn try_event(
 		}
 		Event::MessageCreate(message) => {
 			debug!("received direct message create");
-			let msg = raccord::DirectMessage::from(&**message);
-			let res = if let Some(command) = target.parse_command(&msg.content) {
+			if target.ignores_author(&message.author) {
+				trace!("message author is ignored, dropping");
+				return Ok(());
+			}
+
+			if !target.wants_dm(message.author.id.0, message.channel_id.0, &message.content) {
+				trace!("message author has not opted in to DM forwarding, dropping");
+				return Ok(());
+			}
+
+			let mut msg = raccord::DirectMessage::from(&**message);
+			msg.content_hash = target.hash_content(&msg.content);
+			msg.content_resolved = target.resolve_mentions(&message, &cache, &http).await;
+			msg.opt_in = target.is_dm_opt_in(&msg.content);
+			if let Some(group) = cache.group(message.channel_id) {
+				msg.recipient_count = group.recipients.len();
+			}
+
+			let mut help = None;
+			let res = if let Some(command) = parse_command(&target, &message) {
 				trace!("submitting act: {:?}", command);
-				target.post(raccord::Command {
-					command,
-					message: msg,
-				})
+				help = target.help_response(&command);
+				target.post(command.into_command(msg))
+			} else if target.forward_only_commands() {
+				trace!("message is not a command, dropping");
+				target.count_dropped_message();
+				return Ok(());
 			} else {
 				trace!("submitting act: {:?}", msg);
 				target.post(msg)
 			}?
 			.await?;
+			if reply_help(&res, help, &player, None, message.channel_id).await? {
+				return Ok(());
+			}
+
 			trace!("handing off response: {:?}", res);
 			handle_response(res, player, None, Some(message.channel_id), None).await?;
 		}
+		Event::MessageUpdate(update) if update.guild_id.is_some() => {
+			debug!("received guild message update");
+			let msg = raccord::ServerMessageUpdate::from(&*update);
+			trace!("submitting act: {:?}", msg);
+			let res = target.post(msg)?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(res, player, update.guild_id, Some(update.channel_id), None).await?;
+		}
+		Event::MessageUpdate(update) => {
+			debug!("received direct message update");
+			let msg = raccord::DirectMessageUpdate::from(&*update);
+			trace!("submitting act: {:?}", msg);
+			let res = target.post(msg)?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(res, player, None, Some(update.channel_id), None).await?;
+		}
+		Event::MessageDelete(delete) => {
+			debug!("received message delete");
+			let msg = raccord::MessageDelete {
+				id: delete.id.0,
+				channel_id: delete.channel_id.0,
+				server_id: delete.guild_id.map(|id| id.0),
+			};
+
+			trace!("submitting act: {:?}", msg);
+			let res = target.post(msg)?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(res, player, delete.guild_id, Some(delete.channel_id), None).await?;
+		}
+		Event::MessageDeleteBulk(delete) => {
+			debug!(
+				"received message delete bulk ({} messages)",
+				delete.ids.len()
+			);
+			for id in &delete.ids {
+				let msg = raccord::MessageDelete {
+					id: id.0,
+					channel_id: delete.channel_id.0,
+					server_id: delete.guild_id.map(|id| id.0),
+				};
+
+				trace!("submitting act: {:?}", msg);
+				let res = target.post(msg)?.await?;
+				trace!("handing off response: {:?}", res);
+				handle_response(
+					res,
+					player.clone(),
+					delete.guild_id,
+					Some(delete.channel_id),
+					None,
+				)
+				.await?;
+			}
+		}
 		Event::MemberAdd(mem) => {
 			debug!("received guild member join");
 			let member = raccord::Member::from(&**mem);
@@ -223,25 +820,290 @@ pub async fn try_event(
 			trace!("handing off response: {:?}", res);
 			handle_response(res, player, Some(mem.guild_id), None, None).await?;
 		}
+		Event::MemberRemove(rem) => {
+			debug!("received guild member leave");
+			let leave = raccord::ServerLeave {
+				server_id: rem.guild_id.0,
+				user: raccord::User::from(&rem.user),
+			};
+			trace!("submitting act: {:?}", leave);
+			let res = target.post(leave)?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(res, player, Some(rem.guild_id), None, None).await?;
+		}
+		Event::BanAdd(ban) => {
+			debug!("received guild ban add");
+			let add = raccord::ServerBanAdd::from(&ban);
+			trace!("submitting act: {:?}", add);
+			let res = target.post(add)?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(res, player, Some(ban.guild_id), None, None).await?;
+		}
+		Event::BanRemove(ban) => {
+			debug!("received guild ban remove");
+			let remove = raccord::ServerBanRemove::from(&ban);
+			trace!("submitting act: {:?}", remove);
+			let res = target.post(remove)?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(res, player, Some(ban.guild_id), None, None).await?;
+		}
+		Event::ReactionAdd(reaction) => {
+			debug!("received reaction add");
+			let add = raccord::ReactionAdd::from(&reaction.0);
+			trace!("submitting act: {:?}", add);
+			let res = target.post(add)?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(
+				res,
+				player,
+				reaction.guild_id,
+				Some(reaction.channel_id),
+				Some(reaction.user_id),
+			)
+			.await?;
+		}
+		Event::ReactionRemove(reaction) => {
+			debug!("received reaction remove");
+			let remove = raccord::ReactionRemove::from(&reaction.0);
+			trace!("submitting act: {:?}", remove);
+			let res = target.post(remove)?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(
+				res,
+				player,
+				reaction.guild_id,
+				Some(reaction.channel_id),
+				Some(reaction.user_id),
+			)
+			.await?;
+		}
+		Event::TypingStart(typing) => {
+			debug!("received typing start");
+			let start = raccord::TypingStart::from(&*typing);
+			trace!("submitting act: {:?}", start);
+			let res = target.post(start)?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(
+				res,
+				player,
+				typing.guild_id,
+				Some(typing.channel_id),
+				Some(typing.user_id),
+			)
+			.await?;
+		}
+		Event::ChannelCreate(create) if matches!(create.0, Channel::Private(_)) => {
+			let dm = match create.0 {
+				Channel::Private(ref dm) if dm.kind == ChannelType::Private => dm,
+				_ => return Ok(()),
+			};
+
+			let recipient = match dm.recipients.first() {
+				Some(user) => raccord::User::from(user),
+				None => return Ok(()),
+			};
+
+			debug!("received dm channel create");
+			let open = raccord::DmChannelCreate {
+				channel_id: dm.id.0,
+				recipient,
+			};
+
+			trace!("submitting act: {:?}", open);
+			let res = target.post(open)?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(res, player, None, Some(dm.id), None).await?;
+		}
+		Event::ChannelCreate(create) => {
+			debug!("received channel create");
+			let channel = raccord::ChannelEvent::from(&create.0);
+			trace!("submitting act: {:?}", channel);
+			let (server_id, channel_id) = (channel.server_id.map(GuildId), ChannelId(channel.id));
+			let res = target.post(raccord::ChannelCreated(channel))?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(res, player, server_id, Some(channel_id), None).await?;
+		}
+		Event::ChannelUpdate(update) => {
+			debug!("received channel update");
+			let channel = raccord::ChannelEvent::from(&update.0);
+			trace!("submitting act: {:?}", channel);
+			let (server_id, channel_id) = (channel.server_id.map(GuildId), ChannelId(channel.id));
+			let res = target.post(raccord::ChannelUpdated(channel))?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(res, player, server_id, Some(channel_id), None).await?;
+		}
+		Event::ChannelDelete(delete) => {
+			debug!("received channel delete");
+			let channel = raccord::ChannelEvent::from(&delete.0);
+			trace!("submitting act: {:?}", channel);
+			let server_id = channel.server_id.map(GuildId);
+			let res = target.post(raccord::ChannelDeleted(channel))?.await?;
+			trace!("handing off response: {:?}", res);
+			// the channel is gone, so acts can't default to it
+			handle_response(res, player, server_id, None, None).await?;
+		}
+		Event::MemberChunk(chunk) => {
+			debug!("received guild members chunk");
+			let chunk = raccord::MembersChunk::from(&chunk);
+			trace!("submitting act: {:?}", chunk);
+			let server_id = GuildId(chunk.server_id);
+			let res = target.post(chunk)?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(res, player, Some(server_id), None, None).await?;
+		}
+		Event::VoiceServerUpdate(update) => {
+			debug!("received voice server update");
+			let server_id = match update.guild_id {
+				Some(id) => id,
+				None => return Ok(()),
+			};
+
+			let voice = raccord::VoiceServerUpdate {
+				server_id: server_id.0,
+				token: if target.redact_voice_tokens() {
+					String::from("[REDACTED]")
+				} else {
+					update.token.clone()
+				},
+				endpoint: update.endpoint.clone(),
+			};
+
+			trace!("submitting act: {:?}", voice);
+			let res = target.post(voice)?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(res, player, Some(server_id), None, None).await?;
+		}
+		Event::UserUpdate(update) => {
+			debug!("received bot user update");
+			let user = raccord::User::from(&update.0);
+			trace!("submitting act: {:?}", user);
+			let res = target.post(raccord::BotUpdate(user))?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(res, player, None, None, None).await?;
+		}
 		Event::ShardConnected(_) => {
 			info!("connected on shard {}", shard_id);
 			let res = target.post(raccord::Connected { shard: shard_id })?.await?;
 			trace!("handing off response: {:?}", res);
 			handle_response(res, player, None, None, None).await?;
 		}
+		event if target.forward_raw_events() => {
+			let raw = match raw_event(&event)? {
+				Some(raw) => raw,
+				None => return Ok(()),
+			};
+
+			debug!("received unhandled {} event", raw.event_type);
+			trace!("submitting act: {:?}", raw);
+			let guild_id = event_guild_id(&event);
+			let res = target.post(raw)?.await?;
+			trace!("handing off response: {:?}", res);
+			handle_response(res, player, guild_id, None, None).await?;
+		}
 		_ => {}
 	}
 
 	Ok(())
 }
 
+/// Serialises a gateway dispatch event for `ACCORD_FORWARD_RAW_GATEWAY_EVENTS`.
+///
+/// Returns `None` for events which aren't dispatched by Discord (shard and heartbeat events).
+fn raw_event(event: &Event) -> Result<Option<raccord::RawEvent>, Error> {
+	let event_type = match event.kind().name() {
+		Some(name) => name.to_string(),
+		None => return Ok(None),
+	};
+
+	let raw = match event {
+		Event::BanAdd(e) => serde_json::to_value(e)?,
+		Event::BanRemove(e) => serde_json::to_value(e)?,
+		Event::ChannelCreate(e) => serde_json::to_value(e)?,
+		Event::ChannelDelete(e) => serde_json::to_value(e)?,
+		Event::ChannelPinsUpdate(e) => serde_json::to_value(e)?,
+		Event::ChannelUpdate(e) => serde_json::to_value(e)?,
+		Event::GuildCreate(e) => serde_json::to_value(e)?,
+		Event::GuildDelete(e) => serde_json::to_value(e)?,
+		Event::GuildEmojisUpdate(e) => serde_json::to_value(e)?,
+		Event::GuildIntegrationsUpdate(e) => serde_json::to_value(e)?,
+		Event::GuildUpdate(e) => serde_json::to_value(e)?,
+		Event::InviteCreate(e) => serde_json::to_value(e)?,
+		Event::InviteDelete(e) => serde_json::to_value(e)?,
+		Event::MemberAdd(e) => serde_json::to_value(e)?,
+		Event::MemberRemove(e) => serde_json::to_value(e)?,
+		Event::MemberUpdate(e) => serde_json::to_value(e)?,
+		Event::MemberChunk(e) => serde_json::to_value(e)?,
+		Event::MessageCreate(e) => serde_json::to_value(e)?,
+		Event::MessageDelete(e) => serde_json::to_value(e)?,
+		Event::MessageDeleteBulk(e) => serde_json::to_value(e)?,
+		Event::MessageUpdate(e) => serde_json::to_value(e)?,
+		Event::PresenceUpdate(e) => serde_json::to_value(e)?,
+		Event::ReactionAdd(e) => serde_json::to_value(e)?,
+		Event::ReactionRemove(e) => serde_json::to_value(e)?,
+		Event::ReactionRemoveAll(e) => serde_json::to_value(e)?,
+		Event::ReactionRemoveEmoji(e) => serde_json::to_value(e)?,
+		Event::Ready(e) => serde_json::to_value(e)?,
+		Event::RoleCreate(e) => serde_json::to_value(e)?,
+		Event::RoleDelete(e) => serde_json::to_value(e)?,
+		Event::RoleUpdate(e) => serde_json::to_value(e)?,
+		Event::TypingStart(e) => serde_json::to_value(e)?,
+		Event::UnavailableGuild(e) => serde_json::to_value(e)?,
+		Event::UserUpdate(e) => serde_json::to_value(e)?,
+		Event::VoiceServerUpdate(e) => serde_json::to_value(e)?,
+		Event::VoiceStateUpdate(e) => serde_json::to_value(e)?,
+		Event::WebhooksUpdate(e) => serde_json::to_value(e)?,
+		_ => serde_json::Value::Null,
+	};
+
+	Ok(Some(raccord::RawEvent { event_type, raw }))
+}
+
+fn parse_command(target: &raccord::Client, message: &Message) -> Option<raccord::CommandMatch> {
+	if target.ignore_self_commands() && target.is_self(message.author.id.0) {
+		trace!("message is from the bot itself, not parsing as command");
+		return None;
+	}
+
+	target.parse_command(&message.content)
+}
+
+/// Replies with the `ACCORD_COMMAND_HELP_RESPONSE` if the target has no handler (404) for a help
+/// command, returning whether it did.
+async fn reply_help<T>(
+	res: &Response<T>,
+	help: Option<String>,
+	player: &Sender<Stage>,
+	from_server: Option<GuildId>,
+	from_channel: ChannelId,
+) -> Result<bool, Error> {
+	let content = match help {
+		Some(content) if res.status() == 404 => content,
+		_ => return Ok(false),
+	};
+
+	debug!("target has no help command, replying with ACCORD_COMMAND_HELP_RESPONSE");
+	player
+		.send(Stage {
+			act: Act::CreateMessage {
+				content,
+				channel_id: None,
+				embeds: Vec::new(),
+			},
+			default_server_id: from_server,
+			default_channel_id: Some(from_channel),
+		})
+		.await?;
+
+	Ok(true)
+}
+
 async fn handle_response<T: Debug + Read + AsyncRead + Unpin>(
 	mut res: Response<T>,
 	player: Sender<Stage>,
 	from_server: Option<GuildId>,
 	from_channel: Option<ChannelId>,
 	_from_user: Option<UserId>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+) -> Result<(), Error> {
 	let status = res.status();
 	if status.is_informational() {
 		warn!("unhandled information code {:?}", status);
@@ -266,7 +1128,7 @@ async fn handle_response<T: Debug + Read + AsyncRead + Unpin>(
 	}
 
 	if status.is_client_error() || status.is_server_error() {
-		error!(
+		warn!(
 			"error {:?} from target, TODO: more error handling here",
 			status
 		);
@@ -302,33 +1164,40 @@ async fn handle_response<T: Debug + Read + AsyncRead + Unpin>(
 				.and_then(|s| u64::from_str(s).ok())
 				.map(ChannelId)
 				.or(from_channel);
-			let has_content_length = res
-				.headers()
-				.get("content-length")
-				.and_then(|s| s.to_str().ok())
-				.and_then(|s| usize::from_str(s).ok())
-				.unwrap_or(0) > 0;
+			let has_content_length =
+				res.headers()
+					.get("content-length")
+					.and_then(|s| s.to_str().ok())
+					.and_then(|s| usize::from_str(s).ok())
+					.unwrap_or(0) > 0;
 
 			if has_content_length {
 				info!("response has content-length, parsing single act");
-				let act: Act = res.json()?;
-				trace!("parsed act: {:?}", &act);
-				player
-					.send(Stage {
-						act,
-						default_server_id,
-						default_channel_id,
-					})
-					.await?;
+				let reply: Reply = res.json()?;
+				trace!("parsed reply: {:?}", &reply);
+				if let Some(act) = reply.into_act() {
+					player
+						.send(Stage {
+							act,
+							default_server_id,
+							default_channel_id,
+						})
+						.await?;
+				}
 			} else {
 				info!("response has no content-length, streaming multiple acts");
 				let mut lines = BufReader::new(res.into_body()).lines();
-				loop {
-					if let Some(line) = lines.next().await {
-						let line = line?;
-						trace!("got line: {:?}", line);
-						let act: Act = serde_json::from_str(line.trim())?;
-						trace!("parsed act: {:?}", &act);
+				while let Some(line) = lines.next().await {
+					let line = line?;
+					trace!("got line: {:?}", line);
+					let line = line.trim();
+					if line.is_empty() {
+						continue;
+					}
+
+					let reply: Reply = serde_json::from_str(line)?;
+					trace!("parsed reply: {:?}", &reply);
+					if let Some(act) = reply.into_act() {
 						player
 							.send(Stage {
 								act,
@@ -336,8 +1205,6 @@ async fn handle_response<T: Debug + Read + AsyncRead + Unpin>(
 								default_channel_id,
 							})
 							.await?;
-					} else {
-						break;
 					}
 				}
 				info!("done streaming");
@@ -345,6 +1212,11 @@ async fn handle_response<T: Debug + Read + AsyncRead + Unpin>(
 		}
 		(mime::TEXT, mime::PLAIN) => {
 			let content = res.text()?;
+			if content.trim().is_empty() {
+				trace!("empty reply, no action");
+				return Ok(());
+			}
+
 			let header_channel = res
 				.headers()
 				.get("accord-channel-id")
@@ -356,6 +1228,7 @@ async fn handle_response<T: Debug + Read + AsyncRead + Unpin>(
 					act: Act::CreateMessage {
 						content,
 						channel_id: header_channel,
+						embeds: Vec::new(),
 					},
 					default_server_id: from_server,
 					default_channel_id: from_channel,
@@ -1,10 +1,15 @@
 #![doc(html_favicon_url = "https://raw.githubusercontent.com/passcod/accord/main/res/logo.png")]
 #![doc(html_logo_url = "https://raw.githubusercontent.com/passcod/accord/main/res/logo.png")]
 
+pub use error::Error;
 pub use forward::Forward;
 
 pub mod act;
+pub mod cache;
+pub mod config;
 pub mod error;
 pub mod forward;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod raccord;
 pub mod reverse;
@@ -0,0 +1,130 @@
+//! Prometheus metrics about requests to the target, with the `metrics` feature.
+
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounterVec, Opts, Registry, TextEncoder};
+use std::{collections::HashMap, sync::OnceLock, time::Duration};
+use tide::{Response, Server, StatusCode};
+use tide_tracing::TraceMiddleware;
+
+use crate::{config::Config, error::Error};
+
+struct Metrics {
+	forwarded: IntCounterVec,
+	errors: IntCounterVec,
+	duration: Histogram,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+impl Metrics {
+	/// Builds the metrics with `labels` on each, and registers them with `registry`.
+	fn new(registry: &Registry, labels: HashMap<String, String>) -> prometheus::Result<Self> {
+		let metrics = Self {
+			forwarded: IntCounterVec::new(
+				Opts::new(
+					"accord_events_forwarded_total",
+					"Events successfully posted to the target",
+				)
+				.const_labels(labels.clone()),
+				&["event_type"],
+			)?,
+			errors: IntCounterVec::new(
+				Opts::new(
+					"accord_forward_errors_total",
+					"Events which failed to be posted to the target",
+				)
+				.const_labels(labels.clone()),
+				&["event_type"],
+			)?,
+			duration: Histogram::with_opts(
+				HistogramOpts::new(
+					"accord_forward_duration_seconds",
+					"Time taken to post events to the target, including retries",
+				)
+				.const_labels(labels),
+			)?,
+		};
+
+		registry.register(Box::new(metrics.forwarded.clone()))?;
+		registry.register(Box::new(metrics.errors.clone()))?;
+		registry.register(Box::new(metrics.duration.clone()))?;
+		Ok(metrics)
+	}
+
+	fn record(&self, event_type: &str, ok: bool, elapsed: Duration) {
+		if ok {
+			self.forwarded.with_label_values(&[event_type]).inc();
+		} else {
+			self.errors.with_label_values(&[event_type]).inc();
+		}
+
+		self.duration.observe(elapsed.as_secs_f64());
+	}
+}
+
+/// Registers the metrics, with the constant labels from `ACCORD_METRIC_LABELS` (a JSON object),
+/// so they're exported (at zero) before any event is posted.
+///
+/// # Panics
+///
+/// Will panic if the setting is present but isn't a JSON object of strings.
+pub fn init(config: &Config) {
+	let labels = config
+		.var("ACCORD_METRIC_LABELS")
+		.map(|s| serde_json::from_str(&s).expect("FATAL: bad value: ACCORD_METRIC_LABELS"))
+		.unwrap_or_default();
+	METRICS.get_or_init(|| Metrics::new(prometheus::default_registry(), labels).unwrap());
+}
+
+fn metrics() -> &'static Metrics {
+	METRICS.get_or_init(|| Metrics::new(prometheus::default_registry(), HashMap::new()).unwrap())
+}
+
+/// Records the outcome of posting an event (named by its payload type) to the target.
+pub fn record(event_type: &str, ok: bool, elapsed: Duration) {
+	metrics().record(event_type, ok, elapsed);
+}
+
+/// Serves `/metrics` in the Prometheus text format.
+pub async fn server(bind: String) -> Result<(), Error> {
+	let mut app = Server::new();
+	app.with(TraceMiddleware::new());
+
+	app.at("/metrics").get(|_| async {
+		let mut buf = Vec::new();
+		let encoder = TextEncoder::new();
+		encoder.encode(&prometheus::gather(), &mut buf)?;
+		Ok(Response::builder(StatusCode::Ok)
+			.content_type(encoder.format_type())
+			.body(buf)
+			.build())
+	});
+
+	app.listen(bind).await?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn const_labels_are_added() {
+		let registry = Registry::new();
+		let labels = [(String::from("env"), String::from("test"))].into();
+		let metrics = Metrics::new(&registry, labels).unwrap();
+		metrics.record("ServerMessage", true, Duration::from_millis(5));
+
+		let families = registry.gather();
+		let forwarded = families
+			.iter()
+			.find(|family| family.get_name() == "accord_events_forwarded_total")
+			.unwrap();
+		let labels = forwarded.get_metric()[0].get_label();
+		assert!(labels
+			.iter()
+			.any(|label| label.get_name() == "env" && label.get_value() == "test"));
+		assert!(labels
+			.iter()
+			.any(|label| label.get_name() == "event_type" && label.get_value() == "ServerMessage"));
+	}
+}
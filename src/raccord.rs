@@ -1,12 +1,33 @@
+use async_std::{future::timeout, io::prelude::WriteExt};
+use futures::future::{join, join_all, BoxFuture, FutureExt};
+use hmac::{Hmac, Mac, NewMac};
 use isahc::{
-	config::{Configurable, RedirectPolicy},
-	http::request::{Builder as RequestBuilder, Request},
-	HttpClient, ResponseFuture,
+	auth::{Authentication, Credentials},
+	config::{Configurable, IpVersion, RedirectPolicy, SslOption},
+	http::{
+		request::{Builder as RequestBuilder, Request},
+		Response, Uri,
+	},
+	Body, HttpClient, HttpClientBuilder,
 };
-use regex::Regex;
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
-use std::{error::Error, fmt, time::Duration};
-use tracing::{info, trace};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+	collections::{HashMap, HashSet},
+	convert::TryFrom,
+	env, fmt,
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+		Arc, Mutex, OnceLock,
+	},
+	time::{Duration, Instant},
+};
+use tracing::{debug, error, info, trace, warn};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_http::Client as DisHttpClient;
 use twilight_model::{
 	channel::{
 		embed::Embed,
@@ -14,28 +35,95 @@ use twilight_model::{
 			Message as DisMessage, MessageApplication, MessageFlags as DisMessageFlags,
 			MessageReaction, MessageType as DisMessageType,
 		},
-		Attachment,
+		Attachment, Channel, ChannelType, GuildChannel, Reaction, ReactionType,
+	},
+	gateway::{
+		payload::{BanAdd, BanRemove, MemberChunk, MessageUpdate, TypingStart as DisTypingStart},
+		presence::Status,
 	},
-	gateway::presence::Status,
-	guild::{Member as DisMember, PartialMember},
+	guild::{Guild, Member as DisMember, PartialMember},
 	id::{ChannelId, GuildId, MessageId, RoleId, UserId},
-	user::User as DisUser,
+	user::{CurrentUser, User as DisUser},
 };
 
+use crate::{config::Config, error::Error};
+
+pub type TargetResponse<'c> = BoxFuture<'c, Result<Response<Body>, Error>>;
+
 pub struct Client {
 	base: String,
+	fanout: Vec<String>,
+	channel_routes: HashMap<u64, String>,
 	command_regex: Option<(Regex, Option<Regex>)>,
+	command_strip_prefix: Option<String>,
+	command_prefix: Option<String>,
+	command_help: Option<(String, String)>,
+	command_case_insensitive: bool,
+	ignore_self_commands: bool,
+	ignore_bots: bool,
+	ignore_self: bool,
+	dm_opt_in_command: Option<String>,
+	dm_opt_ins: Mutex<HashSet<u64>>,
+	dm_opt_in_channels: Mutex<HashSet<u64>>,
+	breaker: CircuitBreaker,
+	filters: Vec<Box<dyn EventFilter>>,
+	guild_list: Option<GuildFilter>,
+	channel_list: Option<ChannelFilter>,
+	limiter: Option<RateLimiter>,
+	max_retries: u32,
+	retry_budget: RetryBudget,
+	retry_base: Duration,
+	request_timeout: Duration,
+	health_check_interval: Duration,
+	pool_min_idle: usize,
+	error_path: Option<String>,
+	tls_skip_verify: bool,
+	forward_mentions_only: bool,
+	max_mentions: Option<usize>,
+	channel_allow_regex: Option<Regex>,
+	channel_types: Option<Vec<u8>>,
+	forward_only_text_channels: bool,
+	guild_discovery_only: bool,
+	disable_cache: bool,
+	ignore_uncached_channels: bool,
+	undiscoverable_guilds: Mutex<HashSet<u64>>,
+	metadata_refresh_interval: Duration,
+	guild_metadata: Mutex<HashMap<u64, ServerMetadata>>,
+	guild_features_header: Option<String>,
+	cache: OnceLock<InMemoryCache>,
+	attachment_extensions: Option<Vec<String>>,
+	filter_attachments_only: bool,
+	serialize_nulls: bool,
+	payload_version: u32,
+	embed_urls_only: bool,
+	embed_forwarding: EmbedForwarding,
+	content_hash: Option<ContentHash>,
+	auth: AuthChain,
+	forward_reactions_for_bots: bool,
+	forward_only_commands: bool,
+	forward_bot_commands_only: bool,
+	ignore_partial_messages: bool,
+	redact_voice_tokens: bool,
+	forward_raw_events: bool,
+	resolve_mentions: bool,
+	resolve_mentions_http_fallback: bool,
+	guild_shard_routing: bool,
+	dropped_messages: Mutex<(Instant, u64)>,
+	bot_id: AtomicU64,
+	shard_count: AtomicU64,
+	draining: AtomicBool,
+	in_flight: Arc<AtomicUsize>,
+	dead_letters: Option<Arc<DeadLetters>>,
+	batch_max: usize,
+	batch_interval: Duration,
+	batch_event_types: Option<HashSet<String>>,
+	batch: Mutex<Vec<Buffered>>,
 	client: HttpClient,
 }
 
 impl Client {
 	pub fn new(base: String, command_match: Option<String>, command_parse: Option<String>) -> Self {
-		let client = HttpClient::builder()
-			.default_header("accord-version", env!("CARGO_PKG_VERSION"))
-			.redirect_policy(RedirectPolicy::Limit(8))
-			.auto_referer()
-			.tcp_keepalive(Duration::from_secs(15))
-			.tcp_nodelay()
+		let client = http_builder()
 			.build()
 			.expect("FATAL: failed to create http client");
 		let command_match_regex = command_match
@@ -49,286 +137,2729 @@ impl Client {
 
 		Self {
 			base,
+			fanout: Vec::new(),
+			channel_routes: HashMap::new(),
 			command_regex,
+			command_strip_prefix: None,
+			command_prefix: None,
+			command_help: None,
+			command_case_insensitive: false,
+			ignore_self_commands: true,
+			ignore_bots: false,
+			ignore_self: false,
+			dm_opt_in_command: None,
+			dm_opt_ins: Mutex::new(HashSet::new()),
+			dm_opt_in_channels: Mutex::new(HashSet::new()),
+			breaker: CircuitBreaker::default(),
+			filters: Vec::new(),
+			guild_list: None,
+			channel_list: None,
+			limiter: None,
+			max_retries: 3,
+			retry_budget: RetryBudget::new(60),
+			retry_base: Duration::from_millis(100),
+			request_timeout: Duration::from_millis(5000),
+			health_check_interval: Duration::from_secs(30),
+			pool_min_idle: 1,
+			error_path: None,
+			tls_skip_verify: false,
+			forward_mentions_only: false,
+			max_mentions: None,
+			channel_allow_regex: None,
+			channel_types: None,
+			forward_only_text_channels: false,
+			guild_discovery_only: false,
+			disable_cache: false,
+			ignore_uncached_channels: false,
+			undiscoverable_guilds: Mutex::new(HashSet::new()),
+			metadata_refresh_interval: Duration::from_secs(0),
+			guild_metadata: Mutex::new(HashMap::new()),
+			guild_features_header: None,
+			cache: OnceLock::new(),
+			attachment_extensions: None,
+			filter_attachments_only: false,
+			serialize_nulls: true,
+			payload_version: 1,
+			embed_urls_only: false,
+			embed_forwarding: EmbedForwarding::Full,
+			content_hash: None,
+			auth: AuthChain::default(),
+			forward_reactions_for_bots: true,
+			forward_only_commands: false,
+			forward_bot_commands_only: false,
+			ignore_partial_messages: false,
+			redact_voice_tokens: true,
+			forward_raw_events: false,
+			resolve_mentions: false,
+			resolve_mentions_http_fallback: false,
+			guild_shard_routing: false,
+			dropped_messages: Mutex::new((Instant::now(), 0)),
+			bot_id: AtomicU64::new(0),
+			shard_count: AtomicU64::new(0),
+			draining: AtomicBool::new(false),
+			in_flight: Arc::new(AtomicUsize::new(0)),
+			dead_letters: None,
+			batch_max: 1,
+			batch_interval: Duration::from_secs(1),
+			batch_event_types: None,
+			batch: Mutex::new(Vec::new()),
 			client,
 		}
 	}
 
-	pub fn parse_command(&self, content: &str) -> Option<Vec<String>> {
-		self.command_regex.as_ref().and_then(|(matcher, parser)| {
-			if !matcher.is_match(content) {
-				None
-			} else if let Some(px) = parser {
-				Some(
-					px.captures_iter(content)
-						.map(|captures| -> Vec<String> {
-							captures
-								.iter()
-								.skip(1)
-								.flat_map(|m| m.map(|m| m.as_str().to_string()))
-								.collect()
-						})
-						.flatten()
-						.collect(),
-				)
-			} else {
-				Some(Vec::new())
-			}
-		})
-	}
+	/// Create a client configured from the `ACCORD_*` settings, in the environment or the
+	/// configuration file.
+	///
+	/// # Panics
+	///
+	/// Will panic if both `ACCORD_TARGETS` and `ACCORD_TARGET` are missing, or if any variable is
+	/// present but invalid.
+	pub fn from_config(config: &Config) -> Self {
+		let mut targets: Vec<String> = config
+			.var("ACCORD_TARGETS")
+			.map(|s| {
+				s.split(',')
+					.map(str::trim)
+					.filter(|target| !target.is_empty())
+					.map(String::from)
+					.collect()
+			})
+			.unwrap_or_default();
+		if targets.is_empty() {
+			targets.push(
+				config
+					.var("ACCORD_TARGET")
+					.expect("FATAL: missing env: ACCORD_TARGET"),
+			);
+		}
 
-	pub fn get<S: Sendable>(
-		&self,
-		payload: S,
-	) -> Result<ResponseFuture, Box<dyn Error + Send + Sync>> {
-		trace!(
-			payload_type = std::any::type_name::<S>(),
-			"constructing request"
+		let base = targets.remove(0);
+		let command_match = config.var("ACCORD_COMMAND_MATCH");
+		let command_parse = config.var("ACCORD_COMMAND_PARSE");
+
+		let mut client = Self::new(base, command_match, command_parse);
+		client.fanout = targets;
+		client.channel_routes = config
+			.var("ACCORD_CHANNEL_ROUTES")
+			.map(|s| {
+				s.split(';')
+					.map(str::trim)
+					.filter(|route| !route.is_empty())
+					.map(|route| {
+						route
+							.split_once('=')
+							.and_then(|(id, url)| {
+								Some((id.trim().parse().ok()?, url.trim().into()))
+							})
+							.expect("FATAL: bad value: ACCORD_CHANNEL_ROUTES")
+					})
+					.collect()
+			})
+			.unwrap_or_default();
+		client.command_strip_prefix = config.var("ACCORD_COMMAND_STRIP_PREFIX");
+		client.command_prefix = config
+			.var("ACCORD_COMMAND_PREFIX")
+			.filter(|prefix| !prefix.is_empty());
+		if let (Some(trigger), Some(response)) = (
+			config.var("ACCORD_COMMAND_HELP_TRIGGER"),
+			config.var("ACCORD_COMMAND_HELP_RESPONSE"),
+		) {
+			client.command_help = Some((trigger, response));
+		}
+		if client.command_prefix.is_some() && client.command_regex.is_some() {
+			warn!("both ACCORD_COMMAND_MATCH and ACCORD_COMMAND_PREFIX are set, ignoring ACCORD_COMMAND_PREFIX");
+			client.command_prefix = None;
+		}
+		if config
+			.flag("ACCORD_COMMAND_CASE_INSENSITIVE")
+			.unwrap_or(false)
+		{
+			client.ignore_command_case();
+		}
+		client.ignore_self_commands = config.flag("ACCORD_IGNORE_SELF_COMMANDS").unwrap_or(true);
+		client.ignore_bots = config.flag("ACCORD_IGNORE_BOTS").unwrap_or(false);
+		client.ignore_self = config.flag("ACCORD_IGNORE_SELF").unwrap_or(false);
+		if config.flag("ACCORD_DM_OPT_IN_REQUIRED").unwrap_or(false) {
+			client.dm_opt_in_command = Some(
+				config
+					.var("ACCORD_DM_OPT_IN_COMMAND")
+					.unwrap_or_else(|| "!dmenable".into()),
+			);
+		}
+
+		client.breaker = CircuitBreaker::new(
+			config
+				.parse("ACCORD_CIRCUIT_BREAKER_THRESHOLD")
+				.unwrap_or(10),
+			Duration::from_secs(
+				config
+					.parse("ACCORD_CIRCUIT_BREAKER_RESET_SECS")
+					.unwrap_or(30),
+			),
+		);
+		client.max_retries = config.parse("ACCORD_MAX_RETRIES").unwrap_or(3);
+		client.retry_budget =
+			RetryBudget::new(config.parse("ACCORD_MAX_RETRIES_PER_MINUTE").unwrap_or(60));
+		client.retry_base =
+			Duration::from_millis(config.parse("ACCORD_RETRY_BASE_DELAY_MS").unwrap_or(100));
+		client.request_timeout =
+			Duration::from_millis(config.parse("ACCORD_REQUEST_TIMEOUT_MS").unwrap_or(5000));
+		client.health_check_interval = Duration::from_secs(
+			config
+				.parse("ACCORD_HEALTH_CHECK_INTERVAL_SECS")
+				.unwrap_or(30),
 		);
-		let req = payload
-			.customise(
-				Request::get(format!("{}{}", self.base, payload.url()))
-					.header("content-type", "application/json"),
+		client.pool_min_idle = config.parse("ACCORD_TARGET_POOL_MIN_IDLE").unwrap_or(1);
+		client.error_path = config.var("ACCORD_TARGET_ERROR_PATH");
+		client.dead_letters = config.var("ACCORD_DEAD_LETTER_FILE").map(|path| {
+			Arc::new(
+				DeadLetters::open(&path)
+					.unwrap_or_else(|err| panic!("FATAL: cannot open {}: {}", path, err)),
 			)
-			.body(())?;
-		info!(
-			to = payload.url().as_str(),
-			"sending {}",
-			std::any::type_name::<S>()
+		});
+		client.limiter = config.parse("ACCORD_RATE_LIMIT_RPS").map(|rps: f64| {
+			RateLimiter::new(rps, config.parse("ACCORD_RATE_LIMIT_BURST").unwrap_or(rps))
+		});
+		client.forward_mentions_only = config.flag("ACCORD_FORWARD_MENTIONS_ONLY").unwrap_or(false);
+		client.max_mentions = config.parse("ACCORD_MAX_MENTIONS").filter(|&max| max > 0);
+		// allowlists take precedence over denylists
+		client.guild_list = match (
+			config.ids("ACCORD_ALLOW_GUILDS"),
+			config.ids("ACCORD_DENY_GUILDS"),
+		) {
+			(Some(ids), _) => Some(GuildFilter { ids, deny: false }),
+			(None, Some(ids)) => Some(GuildFilter { ids, deny: true }),
+			(None, None) => None,
+		};
+		client.channel_list = match (
+			config.ids("ACCORD_ALLOW_CHANNELS"),
+			config.ids("ACCORD_DENY_CHANNELS"),
+		) {
+			(Some(ids), _) => Some(ChannelFilter { ids, deny: false }),
+			(None, Some(ids)) => Some(ChannelFilter { ids, deny: true }),
+			(None, None) => None,
+		};
+		client.channel_allow_regex = config
+			.var("ACCORD_CHANNEL_ALLOW_REGEX")
+			.map(|s| Regex::new(&s).expect("FATAL: bad regex: ACCORD_CHANNEL_ALLOW_REGEX"));
+		client.channel_types = config.var("ACCORD_FORWARD_CHANNEL_TYPES").map(|s| {
+			s.split(',')
+				.map(str::trim)
+				.filter(|kind| !kind.is_empty())
+				.map(|kind| {
+					channel_type(kind).expect("FATAL: bad value: ACCORD_FORWARD_CHANNEL_TYPES")
+				})
+				.collect()
+		});
+		client.attachment_extensions = config.var("ACCORD_ATTACHMENT_EXTENSIONS_ALLOW").map(|s| {
+			s.split(',')
+				.map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+				.filter(|ext| !ext.is_empty())
+				.collect()
+		});
+		client.filter_attachments_only = config
+			.flag("ACCORD_FILTER_ATTACHMENTS_ONLY")
+			.unwrap_or(false);
+		client.payload_version = config.parse("ACCORD_PAYLOAD_VERSION").unwrap_or(1);
+		client.serialize_nulls = config.flag("ACCORD_SERIALIZE_NULLS").unwrap_or(true);
+		client.embed_urls_only = config.flag("ACCORD_EMBED_URLS_ONLY").unwrap_or(false);
+		client.embed_forwarding = match config.var("ACCORD_EMBED_FORWARDING").as_deref() {
+			None | Some("full") => EmbedForwarding::Full,
+			Some("urls") => EmbedForwarding::Urls,
+			Some("none") => EmbedForwarding::None,
+			Some(_) => panic!("FATAL: bad value: ACCORD_EMBED_FORWARDING"),
+		};
+		client.content_hash = match config.var("ACCORD_CONTENT_HASH_ALGORITHM").as_deref() {
+			None | Some("none") => None,
+			Some("sha256") => Some(ContentHash::Sha256),
+			Some("md5") => Some(ContentHash::Md5),
+			Some(_) => panic!("FATAL: bad value: ACCORD_CONTENT_HASH_ALGORITHM"),
+		};
+		client.auth = config
+			.var("ACCORD_AUTH_CHAIN")
+			.map(|s| s.parse().expect("FATAL: bad value: ACCORD_AUTH_CHAIN"))
+			.unwrap_or_default();
+		if let Some(secret) = config.var("ACCORD_HMAC_SECRET") {
+			client.auth.push(HmacSha256Auth {
+				secret,
+				header: signature_header(),
+			});
+		}
+		client.forward_only_text_channels = config
+			.flag("ACCORD_FORWARD_ONLY_TEXT_CHANNELS")
+			.unwrap_or(false);
+		client.guild_discovery_only = config.flag("ACCORD_GUILD_DISCOVERY_ONLY").unwrap_or(false);
+		client.metadata_refresh_interval = Duration::from_secs(
+			config
+				.parse("ACCORD_GUILD_METADATA_REFRESH_INTERVAL_SECS")
+				.unwrap_or(0),
 		);
-		Ok(self.client.send_async(req))
-	}
+		client.guild_features_header = config.var("ACCORD_GUILD_FEATURES_HEADER");
+		client.disable_cache = config.flag("ACCORD_DISABLE_CACHE").unwrap_or(false);
+		client.ignore_uncached_channels = config
+			.flag("ACCORD_IGNORE_CHANNELS_WITHOUT_CACHE")
+			.unwrap_or(false);
+		if client.disable_cache && client.ignore_uncached_channels {
+			warn!("ACCORD_IGNORE_CHANNELS_WITHOUT_CACHE is set with ACCORD_DISABLE_CACHE: no server messages will be forwarded");
+		}
+		client.forward_reactions_for_bots = config
+			.flag("ACCORD_FORWARD_REACTIONS_FOR_BOTS")
+			.unwrap_or(true);
+		client.forward_only_commands = config.flag("ACCORD_FORWARD_ONLY_COMMANDS").unwrap_or(false);
+		client.forward_bot_commands_only = config
+			.flag("ACCORD_FORWARD_BOT_COMMANDS_ONLY")
+			.unwrap_or(false);
+		client.ignore_partial_messages = config
+			.flag("ACCORD_IGNORE_PARTIAL_MESSAGES")
+			.unwrap_or(false);
+		client.redact_voice_tokens = config.flag("ACCORD_REDACT_VOICE_TOKENS").unwrap_or(true);
+		client.forward_raw_events = config
+			.flag("ACCORD_FORWARD_RAW_GATEWAY_EVENTS")
+			.unwrap_or(false);
+		client.resolve_mentions = config.flag("ACCORD_RESOLVE_MENTIONS").unwrap_or(false);
+		client.resolve_mentions_http_fallback = config
+			.flag("ACCORD_RESOLVE_MENTIONS_HTTP_FALLBACK")
+			.unwrap_or(false);
+		client.guild_shard_routing = config.flag("ACCORD_GUILD_SHARD_ROUTING").unwrap_or(false);
+		client.batch_max = config.parse("ACCORD_BATCH_MAX").unwrap_or(1);
+		if let Some(ms) = config.parse("ACCORD_BATCH_INTERVAL_MS") {
+			client.batch_interval = Duration::from_millis(ms);
+		}
+		client.batch_event_types = config.var("ACCORD_BATCH_EVENT_TYPES").map(|s| {
+			s.split(',')
+				.map(event_type_key)
+				.filter(|kind| !kind.is_empty())
+				.collect()
+		});
 
-	pub fn post<S: Sendable>(
-		&self,
-		payload: S,
-	) -> Result<ResponseFuture, Box<dyn Error + Send + Sync>> {
-		trace!(
-			payload_type = std::any::type_name::<S>(),
-			"constructing request"
-		);
-		let req = payload
-			.customise(
-				Request::post(format!("{}{}", self.base, payload.url()))
-					.header("content-type", "application/json"),
+		let mut http = http_builder();
+		client.tls_skip_verify = config.flag("ACCORD_TLS_SKIP_VERIFY").unwrap_or(false);
+		if client.tls_skip_verify {
+			warn!("ACCORD_TLS_SKIP_VERIFY is set: target certificates will NOT be verified, this is insecure and must not be used in production");
+			http = http.ssl_options(
+				SslOption::DANGER_ACCEPT_INVALID_CERTS | SslOption::DANGER_ACCEPT_INVALID_HOSTS,
+			);
+		}
+		if let Some(ms) = config.parse("ACCORD_TARGET_CONNECT_TIMEOUT_MS") {
+			http = http.connect_timeout(Duration::from_millis(ms));
+		}
+		if config.flag("ACCORD_TARGET_IPV4_ONLY").unwrap_or(false) {
+			http = http.ip_version(IpVersion::V4);
+		}
+		http = http
+			.connection_cache_size(
+				config
+					.parse("ACCORD_TARGET_POOL_MAX_IDLE")
+					.or_else(|| config.parse("ACCORD_HTTP_POOL_SIZE"))
+					.unwrap_or(10),
 			)
-			.body(serde_json::to_vec(&payload)?)?;
-		info!(
-			to = payload.url().as_str(),
-			"sending {}",
-			std::any::type_name::<S>()
-		);
-		Ok(self.client.send_async(req))
+			.connection_cache_ttl(Duration::from_secs(
+				config
+					.parse("ACCORD_TARGET_POOL_IDLE_TIMEOUT_SECS")
+					.unwrap_or(60),
+			));
+		if let (Some(user), Some(pass)) = (
+			config.var("ACCORD_TARGET_BASIC_AUTH_USER"),
+			config.var("ACCORD_TARGET_BASIC_AUTH_PASS"),
+		) {
+			http = http
+				.authentication(Authentication::basic())
+				.credentials(Credentials::new(user, pass));
+		}
+		client.client = http.build().expect("FATAL: failed to create http client");
+
+		client
 	}
-}
 
-pub trait Sendable: Serialize {
-	fn url(&self) -> String;
+	/// The target base URL, with any credentials and query string removed.
+	pub fn redacted_base(&self) -> String {
+		match self.base.parse::<Uri>() {
+			Ok(uri) => format!(
+				"{}://{}{}{}",
+				uri.scheme_str().unwrap_or("http"),
+				uri.host().unwrap_or_default(),
+				uri.port()
+					.map(|port| format!(":{}", port))
+					.unwrap_or_default(),
+				uri.path().trim_end_matches('/')
+			),
+			Err(_) => String::from("[unparseable]"),
+		}
+	}
 
-	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
-		req
+	/// The user ID of the bot this client is forwarding for, once known.
+	pub fn bot_id(&self) -> Option<u64> {
+		match self.bot_id.load(Ordering::Relaxed) {
+			0 => None,
+			id => Some(id),
+		}
 	}
-}
 
-#[derive(Clone, Debug, Serialize)]
-pub struct Connecting;
+	pub fn set_bot_id(&self, id: u64) {
+		self.bot_id.store(id, Ordering::Relaxed);
+	}
 
-impl Sendable for Connecting {
-	fn url(&self) -> String {
-		"/discord/connecting".to_string()
+	pub fn set_shard_count(&self, count: u64) {
+		self.shard_count.store(count, Ordering::Relaxed);
 	}
-}
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-pub enum Activity {
-	Playing { name: String },
-	Streaming { name: String },
-	Listening { name: String },
-	Watching { name: String },
-	Custom { name: String },
-}
+	/// Whether the user is the bot itself.
+	pub fn is_self(&self, user_id: u64) -> bool {
+		self.bot_id() == Some(user_id)
+	}
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-pub struct Presence {
-	pub afk: Option<bool>,
-	pub activity: Option<Activity>,
-	pub since: Option<u64>,
-	pub status: Option<Status>,
-}
+	pub fn ignore_self_commands(&self) -> bool {
+		self.ignore_self_commands
+	}
 
-#[derive(Clone, Debug, Serialize)]
-pub struct Connected {
-	pub shard: u64,
-}
+	/// Whether messages from this author are dropped, per `ACCORD_IGNORE_BOTS` and `ACCORD_IGNORE_SELF`.
+	pub fn ignores_author(&self, author: &DisUser) -> bool {
+		(self.ignore_bots && author.bot) || (self.ignore_self && self.is_self(author.id.0))
+	}
 
-impl Sendable for Connected {
-	fn url(&self) -> String {
-		"/discord/connected".to_string()
+	/// Whether a direct message is the `ACCORD_DM_OPT_IN_COMMAND`, when opting in is required.
+	pub fn is_dm_opt_in(&self, content: &str) -> bool {
+		self.dm_opt_in_command
+			.as_deref()
+			.is_some_and(|command| content.trim() == command)
 	}
-}
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct User {
-	pub id: u64,
-	pub name: String,
-	#[serde(default)]
-	pub discriminator: String,
-	#[serde(default)]
-	pub bot: bool,
-}
+	/// Whether a direct message passes `ACCORD_DM_OPT_IN_REQUIRED`, recording the author as opted
+	/// in if the message is the opt-in command.
+	///
+	/// The channel of a passing message is also recorded, so that events which don't carry a
+	/// user (like deletes) can be checked with [`Client::wants_dm_event`].
+	///
+	/// Opt-ins are only kept in memory, so users need to opt in again after a restart.
+	pub fn wants_dm(&self, author_id: u64, channel_id: u64, content: &str) -> bool {
+		if self.dm_opt_in_command.is_none() {
+			return true;
+		}
 
-impl From<&DisUser> for User {
-	fn from(dis: &DisUser) -> Self {
-		Self {
-			id: dis.id.0,
-			discriminator: dis.discriminator.clone(),
-			name: dis.name.clone(),
-			bot: dis.bot,
+		let mut opt_ins = self.dm_opt_ins.lock().unwrap();
+		if self.is_dm_opt_in(content) {
+			if opt_ins.insert(author_id) {
+				info!(user = author_id, "user opted in to forwarding their DMs");
+			}
+		} else if !opt_ins.contains(&author_id) {
+			return false;
 		}
+
+		self.dm_opt_in_channels.lock().unwrap().insert(channel_id);
+		true
 	}
-}
 
-impl From<&User> for DisUser {
-	fn from(rac: &User) -> Self {
-		Self {
-			id: UserId(rac.id),
-			discriminator: rac.discriminator.clone(),
-			name: rac.name.clone(),
-			bot: rac.bot,
+	/// Whether any other event in a DM channel passes `ACCORD_DM_OPT_IN_REQUIRED`.
+	///
+	/// Events from a known user pass if that user has opted in; others pass if an opted in user
+	/// has sent a message in the channel.
+	pub fn wants_dm_event(&self, channel_id: u64, user_id: Option<u64>) -> bool {
+		if self.dm_opt_in_command.is_none() {
+			return true;
+		}
 
-			avatar: Default::default(),
-			email: Default::default(),
-			flags: Default::default(),
-			locale: Default::default(),
-			mfa_enabled: Default::default(),
-			premium_type: Default::default(),
-			public_flags: Default::default(),
-			system: Default::default(),
-			verified: Default::default(),
+		match user_id {
+			Some(id) => self.dm_opt_ins.lock().unwrap().contains(&id),
+			None => self
+				.dm_opt_in_channels
+				.lock()
+				.unwrap()
+				.contains(&channel_id),
 		}
 	}
-}
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Member {
-	pub user: User,
-	pub server_id: u64,
-	#[serde(default)]
-	pub roles: Option<Vec<u64>>,
-	#[serde(default)]
-	pub pseudonym: Option<String>,
-}
+	/// Adds a filter which events must pass to be forwarded, after those already added.
+	pub fn add_filter(&mut self, filter: impl EventFilter + 'static) {
+		self.filters.push(Box::new(filter));
+	}
 
-impl From<&DisMessage> for Member {
-	fn from(dis: &DisMessage) -> Self {
-		Self {
-			user: User::from(&dis.author),
-			server_id: dis.guild_id.map(|g| g.0).unwrap_or_default(),
-			roles: dis
-				.member
+	/// Whether events from a server and channel pass the `ACCORD_ALLOW_*` and `ACCORD_DENY_*` lists.
+	///
+	/// Events without a server or channel always pass that part of the check.
+	pub fn is_allowed(&self, guild_id: Option<u64>, channel_id: Option<u64>) -> bool {
+		let allowed = self
+			.guild_list
+			.as_ref()
+			.is_none_or(|list| list.allow("", guild_id, None, 0))
+			&& self
+				.channel_list
 				.as_ref()
-				.map(|mem| mem.roles.iter().map(|role| role.0).collect()),
-			pseudonym: dis.member.as_ref().and_then(|mem| mem.nick.clone()),
+				.is_none_or(|list| list.allow("", None, channel_id, 0));
+
+		if !allowed {
+			debug!(
+				guild = ?guild_id,
+				channel = ?channel_id,
+				"event is not from an allowed guild or channel, skipping"
+			);
 		}
+
+		allowed
 	}
-}
 
-impl From<&DisMember> for Member {
-	fn from(dis: &DisMember) -> Self {
-		Self {
-			user: User::from(&dis.user),
-			server_id: dis.guild_id.0,
-			roles: Some(dis.roles.iter().map(|role| role.0).collect()),
-			pseudonym: dis.nick.clone(),
+	fn filters_allow(
+		&self,
+		event_type: &str,
+		guild_id: Option<u64>,
+		channel_id: Option<u64>,
+		user_id: u64,
+	) -> bool {
+		let allowed = self
+			.filters
+			.iter()
+			.all(|f| f.allow(event_type, guild_id, channel_id, user_id));
+
+		if !allowed {
+			debug!(
+				event_type,
+				user = user_id,
+				"event rejected by filters, skipping"
+			);
 		}
+
+		allowed
 	}
-}
 
-impl From<&Member> for PartialMember {
-	fn from(rac: &Member) -> Self {
-		Self {
-			roles: rac
-				.roles
-				.as_ref()
-				.map(|v| v.into_iter().map(|r| RoleId(*r)).collect())
-				.unwrap_or_default(),
-			nick: rac.pseudonym.clone(),
+	/// Whether a message update should be forwarded to the target at all.
+	pub fn wants_message_update(&self, update: &MessageUpdate, cache: &InMemoryCache) -> bool {
+		if self.ignore_partial_messages && update.content.is_none() {
+			trace!(message = update.id.0, "partial message update, skipping");
+			return false;
+		}
 
-			deaf: Default::default(),
-			mute: Default::default(),
-			joined_at: Default::default(),
+		if !self.filters_allow(
+			if update.guild_id.is_some() {
+				"server_message_update"
+			} else {
+				"direct_message_update"
+			},
+			update.guild_id.map(|id| id.0),
+			Some(update.channel_id.0),
+			update.author.as_ref().map(|a| a.id.0).unwrap_or_default(),
+		) {
+			return false;
 		}
-	}
-}
 
-#[derive(Clone, Debug, Serialize)]
-pub struct ServerJoin(pub Member);
+		if self.forward_bot_commands_only && update.author.as_ref().is_some_and(|a| a.bot) {
+			debug!(message = update.id.0, "message is from a bot, skipping");
+			return false;
+		}
 
-impl Sendable for ServerJoin {
-	fn url(&self) -> String {
-		format!("/server/{}/join/{}", self.0.server_id, self.0.user.id)
+		if self.forward_mentions_only
+			&& update.mention_everyone != Some(true)
+			&& !self.bot_id().is_some_and(|id| {
+				update
+					.mentions
+					.as_ref()
+					.is_some_and(|mentions| mentions.iter().any(|user| user.id.0 == id))
+			}) {
+			debug!(
+				message = update.id.0,
+				"message update does not mention the bot, skipping"
+			);
+			return false;
+		}
+
+		self.wants_channel(update.guild_id, update.channel_id, cache)
 	}
 
-	fn customise(&self, mut req: RequestBuilder) -> RequestBuilder {
-		req = req
-			.header("accord-server-id", self.0.server_id)
-			.header("accord-member-id", self.0.user.id)
-			.header("accord-member-name", &escape(&self.0.user.name));
+	/// Whether a message deletion should be forwarded to the target at all.
+	pub fn wants_message_delete(
+		&self,
+		guild_id: Option<GuildId>,
+		channel_id: ChannelId,
+		cache: &InMemoryCache,
+	) -> bool {
+		self.filters_allow(
+			"message_delete",
+			guild_id.map(|id| id.0),
+			Some(channel_id.0),
+			0,
+		) && self.wants_channel(guild_id, channel_id, cache)
+	}
 
-		if let Some(ref pseud) = self.0.pseudonym {
-			req = req.header("accord-member-pseudonym", pseud);
+	/// Whether events from a channel should be forwarded, by its name and type.
+	fn wants_channel(
+		&self,
+		guild_id: Option<GuildId>,
+		channel_id: ChannelId,
+		cache: &InMemoryCache,
+	) -> bool {
+		if let (Some(allow), Some(_)) = (&self.channel_allow_regex, guild_id) {
+			let channel = cache.guild_channel(channel_id);
+			let name = channel.as_ref().map(|c| c.name());
+			if !name.is_some_and(|name| allow.is_match(name)) {
+				debug!(
+					channel = channel_id.0,
+					name = ?name,
+					"channel is not allowed by ACCORD_CHANNEL_ALLOW_REGEX, skipping"
+				);
+				return false;
+			}
 		}
 
-		if let Some(ref roles) = self.0.roles {
-			for role in roles {
-				req = req.header("accord-member-role-ids", *role);
+		if let Some(ref allow) = self.channel_types {
+			let kind = if guild_id.is_some() {
+				cache.guild_channel(channel_id).map(|c| match *c {
+					GuildChannel::Category(ref c) => c.kind as u8,
+					GuildChannel::Text(ref c) => c.kind as u8,
+					GuildChannel::Voice(ref c) => c.kind as u8,
+				})
+			} else if cache.group(channel_id).is_some() {
+				Some(3)
+			} else {
+				Some(1)
+			};
+
+			if !kind.is_some_and(|kind| allow.contains(&kind)) {
+				debug!(
+					channel = channel_id.0,
+					kind = ?kind,
+					"channel type is not allowed by ACCORD_FORWARD_CHANNEL_TYPES, skipping"
+				);
+				return false;
 			}
 		}
 
-		req
+		true
 	}
-}
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "kebab-case")]
-pub enum MessageFlag {
-	Crossposted,
-	IsCrosspost,
-	SuppressEmbeds,
-	SourceMessageDeleted,
-	Urgent,
-}
-
-impl MessageFlag {
-	pub fn from_discord(dis: DisMessageFlags) -> Vec<Self> {
-		let mut flags = Vec::with_capacity(5);
-		if dis.contains(DisMessageFlags::CROSSPOSTED) {
-			flags.push(Self::Crossposted);
+	/// Records whether a guild is discoverable, for `ACCORD_GUILD_DISCOVERY_ONLY`.
+	pub fn note_guild(&self, guild: &Guild) {
+		if !self.guild_discovery_only {
+			return;
 		}
-		if dis.contains(DisMessageFlags::IS_CROSSPOST) {
-			flags.push(Self::IsCrosspost);
+
+		let mut denied = self.undiscoverable_guilds.lock().unwrap();
+		if guild
+			.features
+			.iter()
+			.any(|feature| feature == "DISCOVERABLE")
+		{
+			denied.remove(&guild.id.0);
+		} else if denied.insert(guild.id.0) {
+			debug!(
+				guild = guild.id.0,
+				"guild is not discoverable, ignoring its events"
+			);
 		}
-		if dis.contains(DisMessageFlags::SUPPRESS_EMBEDS) {
-			flags.push(Self::SuppressEmbeds);
+	}
+
+	/// Records a guild's name, member count, and features, if
+	/// `ACCORD_GUILD_METADATA_REFRESH_INTERVAL_SECS` is set.
+	pub fn note_guild_metadata(&self, guild: &Guild) {
+		if self.metadata_refresh_interval == Duration::from_secs(0) {
+			return;
 		}
-		if dis.contains(DisMessageFlags::SOURCE_MESSAGE_DELETED) {
-			flags.push(Self::SourceMessageDeleted);
+
+		let mut metadata = self.guild_metadata.lock().unwrap();
+		let member_count = guild
+			.member_count
+			.or(guild.approximate_member_count)
+			// GuildCreate has the exact count, which shouldn't be replaced by a missing one
+			.or_else(|| metadata.get(&guild.id.0)?.member_count);
+		metadata.insert(
+			guild.id.0,
+			ServerMetadata {
+				name: guild.name.clone(),
+				member_count,
+				features: guild.features.clone(),
+			},
+		);
+	}
+
+	/// Stops tracking a guild's metadata, once the bot has left it.
+	pub fn forget_guild_metadata(&self, guild_id: GuildId) {
+		self.guild_metadata.lock().unwrap().remove(&guild_id.0);
+	}
+
+	/// The last known metadata of a guild, if it's being tracked.
+	pub fn guild_metadata(&self, guild_id: u64) -> Option<ServerMetadata> {
+		self.guild_metadata.lock().unwrap().get(&guild_id).cloned()
+	}
+
+	/// Fetches every known guild from the Discord API every
+	/// `ACCORD_GUILD_METADATA_REFRESH_INTERVAL_SECS`, forever.
+	///
+	/// Does nothing if the interval is zero.
+	pub async fn refresh_guild_metadata(self: Arc<Self>, http: DisHttpClient) {
+		if self.metadata_refresh_interval == Duration::from_secs(0) {
+			return;
 		}
-		if dis.contains(DisMessageFlags::URGENT) {
-			flags.push(Self::Urgent);
+
+		loop {
+			async_std::task::sleep(self.metadata_refresh_interval).await;
+
+			let ids: Vec<u64> = self
+				.guild_metadata
+				.lock()
+				.unwrap()
+				.keys()
+				.copied()
+				.collect();
+			debug!("refreshing metadata of {} guilds", ids.len());
+			for id in ids {
+				match http.guild(GuildId(id)).with_counts(true).await {
+					Ok(Some(guild)) => {
+						self.note_guild(&guild);
+						self.note_guild_metadata(&guild);
+					}
+					Ok(None) => self.forget_guild_metadata(GuildId(id)),
+					Err(err) => warn!(guild = id, "failed to refresh guild metadata: {}", err),
+				}
+			}
 		}
-		flags
 	}
 
-	pub fn to_discord(rac: &[Self]) -> Option<DisMessageFlags> {
-		if rac.is_empty() {
-			return None;
+	/// Whether events from a guild pass `ACCORD_GUILD_DISCOVERY_ONLY`.
+	///
+	/// Guilds are only ruled out once seen in a `GuildCreate`.
+	pub fn wants_guild(&self, guild_id: GuildId) -> bool {
+		!self.guild_discovery_only
+			|| !self
+				.undiscoverable_guilds
+				.lock()
+				.unwrap()
+				.contains(&guild_id.0)
+	}
+
+	/// Whether events from a channel pass `ACCORD_FORWARD_ONLY_TEXT_CHANNELS`.
+	///
+	/// Only guild channels known to the cache can be ruled out, so DMs always pass.
+	pub fn wants_text_channel(&self, channel_id: ChannelId, cache: &InMemoryCache) -> bool {
+		if !self.forward_only_text_channels {
+			return true;
 		}
 
-		let mut flags = DisMessageFlags::empty();
-		if rac.contains(&MessageFlag::Crossposted) {
+		let text = cache
+			.guild_channel(channel_id)
+			.is_none_or(|channel| match *channel {
+				GuildChannel::Text(ref c) => {
+					matches!(c.kind, ChannelType::GuildText | ChannelType::GuildNews)
+				}
+				GuildChannel::Category(_) | GuildChannel::Voice(_) => false,
+			});
+
+		if !text {
+			debug!(
+				channel = channel_id.0,
+				"channel is not a text channel, skipping"
+			);
+		}
+
+		text
+	}
+
+	/// Whether a message should be forwarded to the target at all.
+	pub fn wants_message(&self, message: &DisMessage, cache: &InMemoryCache) -> bool {
+		if self.ignore_uncached_channels
+			&& message.guild_id.is_some()
+			&& cache.guild_channel(message.channel_id).is_none()
+		{
+			warn!(
+				message = message.id.0,
+				channel = message.channel_id.0,
+				"channel is not in the cache, skipping"
+			);
+			return false;
+		}
+
+		if !self.filters_allow(
+			if message.guild_id.is_some() {
+				"server_message"
+			} else {
+				"direct_message"
+			},
+			message.guild_id.map(|id| id.0),
+			Some(message.channel_id.0),
+			message.author.id.0,
+		) {
+			return false;
+		}
+
+		if self.forward_bot_commands_only && message.author.bot {
+			debug!(message = message.id.0, "message is from a bot, skipping");
+			return false;
+		}
+
+		if self.forward_mentions_only
+			&& !message.mention_everyone
+			&& !self
+				.bot_id()
+				.is_some_and(|id| message.mentions.contains_key(&UserId(id)))
+		{
+			debug!(
+				message = message.id.0,
+				"message does not mention the bot, skipping"
+			);
+			return false;
+		}
+
+		if let Some(max) = self.max_mentions {
+			let mentions = message.mentions.len() + message.mention_everyone as usize;
+			if mentions > max {
+				warn!(
+					message = message.id.0,
+					mentions, "message has more mentions than ACCORD_MAX_MENTIONS, skipping"
+				);
+				return false;
+			}
+		}
+
+		if !self.wants_channel(message.guild_id, message.channel_id, cache) {
+			return false;
+		}
+
+		if let Some(ref allow) = self.attachment_extensions {
+			if message.attachments.is_empty() {
+				if self.filter_attachments_only {
+					debug!(
+						message = message.id.0,
+						"message has no attachments, skipping"
+					);
+					return false;
+				}
+			} else if !message.attachments.iter().any(|a| {
+				a.filename
+					.rsplit_once('.')
+					.is_some_and(|(_, ext)| allow.contains(&ext.to_lowercase()))
+			}) {
+				debug!(
+					message = message.id.0,
+					"no attachments allowed by ACCORD_ATTACHMENT_EXTENSIONS_ALLOW, skipping"
+				);
+				return false;
+			}
+		}
+
+		true
+	}
+
+	/// Hashes message content with the algorithm from `ACCORD_CONTENT_HASH_ALGORITHM`, if any.
+	pub fn hash_content(&self, content: &str) -> Option<String> {
+		match self.content_hash? {
+			ContentHash::Sha256 => Some(format!("{:x}", Sha256::digest(content.as_bytes()))),
+			ContentHash::Md5 => Some(format!("{:x}", md5::compute(content))),
+		}
+	}
+
+	pub fn forward_only_commands(&self) -> bool {
+		self.forward_only_commands || self.forward_bot_commands_only
+	}
+
+	/// Whether events should be recorded in the cache, which `ACCORD_DISABLE_CACHE` turns off.
+	pub fn cache_enabled(&self) -> bool {
+		!self.disable_cache
+	}
+
+	/// Shares the twilight cache, for the headers that read from it. Only the first call counts.
+	pub fn set_cache(&self, cache: InMemoryCache) {
+		let _ = self.cache.set(cache);
+	}
+
+	pub fn redact_voice_tokens(&self) -> bool {
+		self.redact_voice_tokens
+	}
+
+	pub fn forward_raw_events(&self) -> bool {
+		self.forward_raw_events
+	}
+
+	/// The message content with user, role, and channel mentions replaced by their names, if
+	/// `ACCORD_RESOLVE_MENTIONS=1` is set.
+	///
+	/// Users are named by their nickname in the server if known, or their username, looked up
+	/// with [`Client::resolve_user`] if they're not in the message. Mentions which can't be
+	/// resolved are left as they are.
+	pub async fn resolve_mentions(
+		&self,
+		message: &DisMessage,
+		cache: &InMemoryCache,
+		http: &DisHttpClient,
+	) -> Option<String> {
+		if !self.resolve_mentions {
+			return None;
+		}
+
+		let mut users: HashMap<u64, String> = message
+			.mentions
+			.values()
+			.map(|user| (user.id.0, user.name.clone()))
+			.collect();
+		let missing: HashSet<u64> = mention_regex()
+			.captures_iter(&message.content)
+			.filter(|caps| caps[1].starts_with('@') && &caps[1] != "@&")
+			.filter_map(|caps| caps[2].parse().ok())
+			.filter(|id| !users.contains_key(id))
+			.collect();
+		for id in missing {
+			if let Some(user) = self.resolve_user(id, cache, http).await {
+				users.insert(id, user.name);
+			}
+		}
+
+		Some(
+			mention_regex()
+				.replace_all(&message.content, |caps: &regex::Captures| {
+					let id = match caps[2].parse() {
+						Ok(id) => id,
+						Err(_) => return caps[0].to_string(),
+					};
+
+					let name = match &caps[1] {
+						"@&" => cache.role(RoleId(id)).map(|role| role.name.clone()),
+						"#" => cache
+							.guild_channel(ChannelId(id))
+							.map(|channel| channel.name().to_string()),
+						_ => message
+							.guild_id
+							.and_then(|guild| cache.member(guild, UserId(id)))
+							.and_then(|member| member.nick.clone())
+							.or_else(|| users.get(&id).cloned()),
+					};
+
+					match name {
+						Some(name) if &caps[1] == "#" => format!("#{}", name),
+						Some(name) => format!("@{}", name),
+						None => caps[0].to_string(),
+					}
+				})
+				.into_owned(),
+		)
+	}
+
+	/// Looks up a user in the cache, falling back to the Discord API if
+	/// `ACCORD_RESOLVE_MENTIONS_HTTP_FALLBACK=1` is set.
+	pub async fn resolve_user(
+		&self,
+		id: u64,
+		cache: &InMemoryCache,
+		http: &DisHttpClient,
+	) -> Option<User> {
+		if let Some(user) = cache.user(UserId(id)) {
+			return Some(User::from(&*user));
+		}
+
+		if !self.resolve_mentions_http_fallback {
+			return None;
+		}
+
+		trace!(user = id, "user not in cache, fetching");
+		match http.user(UserId(id)).await {
+			Ok(user) => user.as_ref().map(User::from),
+			Err(err) => {
+				warn!(user = id, "failed to fetch user: {}", err);
+				None
+			}
+		}
+	}
+
+	/// Counts a message dropped for not being a command, logging the tally once a minute.
+	pub fn count_dropped_message(&self) {
+		let mut dropped = self.dropped_messages.lock().unwrap();
+		dropped.1 += 1;
+
+		if dropped.0.elapsed() >= Duration::from_secs(60) {
+			info!(
+				"dropped {} non-command messages in the last {}s",
+				dropped.1,
+				dropped.0.elapsed().as_secs()
+			);
+			*dropped = (Instant::now(), 0);
+		}
+	}
+
+	/// Whether a reaction event should be forwarded to the target at all.
+	pub fn wants_reaction(&self, reaction: &Reaction, cache: &InMemoryCache) -> bool {
+		if !self.filters_allow(
+			"reaction",
+			reaction.guild_id.map(|id| id.0),
+			Some(reaction.channel_id.0),
+			reaction.user_id.0,
+		) {
+			return false;
+		}
+
+		if !self.forward_reactions_for_bots {
+			let bot = reaction
+				.member
+				.as_ref()
+				.map(|mem| mem.user.bot)
+				.or_else(|| cache.user(reaction.user_id).map(|user| user.bot));
+			if bot == Some(true) {
+				debug!(
+					user = reaction.user_id.0,
+					"reaction is from a bot, skipping"
+				);
+				return false;
+			}
+		}
+
+		true
+	}
+
+	/// Makes commands case insensitive, for `ACCORD_COMMAND_CASE_INSENSITIVE`.
+	fn ignore_command_case(&mut self) {
+		self.command_case_insensitive = true;
+		self.command_regex = self.command_regex.take().map(|(matcher, parser)| {
+			(
+				case_insensitive(&matcher),
+				parser.as_ref().map(case_insensitive),
+			)
+		});
+	}
+
+	/// Matches a message against the command regexes, or `ACCORD_COMMAND_PREFIX`.
+	///
+	/// With `ACCORD_COMMAND_CASE_INSENSITIVE`, the match ignores case and the command name is
+	/// lowercased, but arguments and `raw_command` are kept as they were sent.
+	pub fn parse_command(&self, content: &str) -> Option<CommandMatch> {
+		let mut command = self.match_command(content)?;
+		if self.command_case_insensitive {
+			if let Some(name) = command.command.first_mut() {
+				*name = name.to_lowercase();
+			}
+		}
+
+		Some(command)
+	}
+
+	fn match_command(&self, content: &str) -> Option<CommandMatch> {
+		let (matcher, parser) = match self.command_regex {
+			Some(ref regexes) => regexes,
+			None => return self.parse_prefix_command(content),
+		};
+
+		let matched = matcher.find(content)?;
+
+		let total = content.chars().count();
+		let confidence = if total == 0 {
+			1.0
+		} else {
+			matched.as_str().chars().count() as f32 / total as f32
+		};
+
+		let mut command = if let Some(px) = parser {
+			px.captures_iter(content)
+				.flat_map(|captures| -> Vec<String> {
+					captures
+						.iter()
+						.skip(1)
+						.flat_map(|m| m.map(|m| m.as_str().to_string()))
+						.collect()
+				})
+				.collect()
+		} else {
+			Vec::new()
+		};
+
+		if let (Some(prefix), Some(first)) = (&self.command_strip_prefix, command.first_mut()) {
+			if let Some(stripped) = self.strip_command_prefix(first, prefix) {
+				*first = stripped.to_string();
+			}
+		}
+
+		Some(CommandMatch {
+			command,
+			raw_command: matched.as_str().to_string(),
+			confidence,
+		})
+	}
+
+	/// The `ACCORD_COMMAND_HELP_RESPONSE`, if the command is the `ACCORD_COMMAND_HELP_TRIGGER`.
+	pub fn help_response(&self, command: &CommandMatch) -> Option<String> {
+		let (trigger, response) = self.command_help.as_ref()?;
+		if command.command.first() == Some(trigger) {
+			Some(response.clone())
+		} else {
+			None
+		}
+	}
+
+	/// Parses a command with `ACCORD_COMMAND_PREFIX`: the whole message, split on whitespace,
+	/// when its first word starts with the prefix.
+	fn parse_prefix_command(&self, content: &str) -> Option<CommandMatch> {
+		let prefix = self.command_prefix.as_deref()?;
+
+		let mut command: Vec<String> = content.split_whitespace().map(String::from).collect();
+		let name = self.strip_command_prefix(command.first()?, prefix)?;
+		if name.is_empty() {
+			return None;
+		}
+
+		command[0] = name.to_string();
+		Some(CommandMatch {
+			command,
+			raw_command: content.trim().to_string(),
+			confidence: 1.0,
+		})
+	}
+
+	/// Strips a prefix from a command word, ignoring case with `ACCORD_COMMAND_CASE_INSENSITIVE`.
+	fn strip_command_prefix<'w>(&self, word: &'w str, prefix: &str) -> Option<&'w str> {
+		if !self.command_case_insensitive {
+			return word.strip_prefix(prefix);
+		}
+
+		let head = word.get(..prefix.len())?;
+		if head.to_lowercase() == prefix.to_lowercase() {
+			Some(&word[prefix.len()..])
+		} else {
+			None
+		}
+	}
+
+	pub fn get<S: Sendable>(&self, payload: S) -> Result<TargetResponse<'_>, Error> {
+		trace!(
+			payload_type = std::any::type_name::<S>(),
+			"constructing request"
+		);
+		let req = self.prepare(
+			Request::get(format!("{}{}", self.base_for(&payload), payload.url())),
+			&payload,
+		);
+		let req = self.auth.apply(req, &[]).body(())?;
+		info!(
+			to = payload.url().as_str(),
+			"sending {}",
+			std::any::type_name::<S>()
+		);
+		self.send(req)
+	}
+
+	pub fn post<S: Sendable>(&self, payload: S) -> Result<TargetResponse<'_>, Error> {
+		if self.batches(&payload) {
+			return self.buffer(payload.batch_key(), &payload);
+		}
+
+		trace!(
+			payload_type = std::any::type_name::<S>(),
+			"constructing request"
+		);
+		let body = self.serialise(&payload)?;
+		let request_to = |base: &str| {
+			let req = self.prepare(
+				Request::post(format!("{}{}", base, payload.url())),
+				&payload,
+			);
+			// applied here rather than in prepare, as signatures need the final body bytes
+			self.auth.apply(req, &body).body(body.clone())
+		};
+
+		let url = format!("{}{}", self.base_for(&payload), payload.url());
+		let dead_body = self.dead_letters.as_ref().map(|_| body.clone());
+		let req = request_to(self.base_for(&payload))?;
+		let mirrors = self
+			.fanout
+			.iter()
+			.map(|base| request_to(base))
+			.collect::<Result<Vec<_>, _>>()?;
+		info!(
+			to = payload.url().as_str(),
+			"sending {}",
+			std::any::type_name::<S>()
+		);
+
+		let res = if mirrors.is_empty() {
+			self.send(req)
+		} else {
+			self.send(req).map(|main| {
+				async move {
+					let (res, _) = join(
+						main,
+						join_all(mirrors.into_iter().map(|req| self.mirror(req))),
+					)
+					.await;
+					res
+				}
+				.boxed()
+			})
+		};
+
+		let res = match dead_body {
+			Some(body) => self.dead_letter_failures(url, body, res),
+			None => res,
+		};
+		measure(payload.event_type(), res)
+	}
+
+	/// Writes the payload to `ACCORD_DEAD_LETTER_FILE` if the request fails for good: if it is
+	/// discarded, errors, or still gets a 5xx status after all retries.
+	fn dead_letter_failures<'c>(
+		&'c self,
+		url: String,
+		body: Vec<u8>,
+		res: Result<TargetResponse<'c>, Error>,
+	) -> Result<TargetResponse<'c>, Error> {
+		let dead_letters = match self.dead_letters {
+			Some(ref dead_letters) => dead_letters.clone(),
+			None => return res,
+		};
+
+		match res {
+			// the error is deferred to the future, so the dead letter is written before it resolves
+			Err(err) => Ok(async move {
+				dead_letters
+					.write(&DeadLetter::new(url, &body, err.to_string()))
+					.await;
+				Err(err)
+			}
+			.boxed()),
+			Ok(fut) => Ok(async move {
+				let res = fut.await;
+				let error = match res {
+					Err(ref err) => Some(err.to_string()),
+					Ok(ref r) if r.status().is_server_error() => Some(r.status().to_string()),
+					Ok(_) => None,
+				};
+				if let Some(error) = error {
+					dead_letters
+						.write(&DeadLetter::new(url, &body, error))
+						.await;
+				}
+				res
+			}
+			.boxed()),
+		}
+	}
+
+	/// Sends a copy of a request to one of the extra `ACCORD_TARGETS`.
+	///
+	/// Failures are logged and otherwise ignored, and responses are discarded: only the first
+	/// target's response is acted upon.
+	async fn mirror(&self, req: Request<Vec<u8>>) {
+		let uri = req.uri().clone();
+		let _in_flight = InFlight::new(&self.in_flight);
+		match timeout(self.request_timeout, self.client.send_async(req)).await {
+			Ok(Ok(res)) if !res.status().is_server_error() => {
+				trace!(to = %uri, status = %res.status(), "mirrored request");
+			}
+			Ok(Ok(res)) => {
+				warn!(to = %uri, status = %res.status(), "mirror target failed");
+			}
+			Ok(Err(err)) => {
+				warn!(to = %uri, "request to mirror target failed: {}", err);
+			}
+			Err(_) => {
+				warn!(to = %uri, "request to mirror target timed out");
+			}
+		}
+	}
+
+	/// Posts several already-serialised events to the target in one request.
+	pub fn post_batch(&self, events: Vec<Value>) -> Result<TargetResponse<'_>, Error> {
+		self.post(Batch(events))
+	}
+
+	/// Posts payloads in batches grouped by [`Sendable::batch_key`], one request per group.
+	///
+	/// Payloads keep their order within each group, so e.g. messages from one channel stay in order.
+	pub fn post_batches<S: Sendable>(
+		&self,
+		payloads: Vec<S>,
+	) -> Result<Vec<TargetResponse<'_>>, Error> {
+		let mut groups: Vec<(Option<String>, Vec<Value>)> = Vec::new();
+		for payload in payloads {
+			let key = payload.batch_key();
+			let value = serde_json::to_value(&payload)?;
+			match groups.iter_mut().find(|(k, _)| *k == key) {
+				Some((_, values)) => values.push(value),
+				None => groups.push((key, vec![value])),
+			}
+		}
+
+		groups
+			.into_iter()
+			.map(|(_, events)| self.post_batch(events))
+			.collect()
+	}
+
+	/// Whether a payload goes in the batch buffer: with `ACCORD_BATCH_MAX`, if its type is in
+	/// `ACCORD_BATCH_EVENT_TYPES`, or if it has a [`Sendable::batch_key`] when that isn't set.
+	fn batches<S: Sendable>(&self, payload: &S) -> bool {
+		if self.batch_max <= 1 {
+			return false;
+		}
+
+		match self.batch_event_types {
+			Some(ref types) => types.contains(&event_type_key(payload.event_type())),
+			None => payload.batch_key().is_some(),
+		}
+	}
+
+	/// Adds a payload to the batch buffer, posting the buffer once full.
+	///
+	/// Resolves to an empty 204 response, as the target's response to a batch isn't acted upon.
+	fn buffer<S: Sendable>(
+		&self,
+		key: Option<String>,
+		payload: &S,
+	) -> Result<TargetResponse<'_>, Error> {
+		let value = serde_json::from_slice(&self.serialise(payload)?)?;
+		let full = {
+			let mut batch = self.batch.lock().unwrap();
+			batch.push(Buffered { key, value });
+			trace!("buffered payload, {} in batch", batch.len());
+			if batch.len() >= self.batch_max {
+				std::mem::take(&mut *batch)
+			} else {
+				Vec::new()
+			}
+		};
+
+		Ok(async move {
+			self.post_buffered(full).await;
+			Ok(Response::builder().status(204).body(Body::empty())?)
+		}
+		.boxed())
+	}
+
+	/// Posts everything in the batch buffer every `ACCORD_BATCH_INTERVAL_MS`, forever.
+	///
+	/// Returns immediately if batching is disabled.
+	pub async fn flush_batches(self: Arc<Self>) {
+		if self.batch_max <= 1 {
+			return;
+		}
+
+		loop {
+			async_std::task::sleep(self.batch_interval).await;
+			self.flush().await;
+		}
+	}
+
+	/// Posts everything in the batch buffer.
+	pub async fn flush(&self) {
+		let batch = std::mem::take(&mut *self.batch.lock().unwrap());
+		self.post_buffered(batch).await;
+	}
+
+	async fn post_buffered(&self, batch: Vec<Buffered>) {
+		if batch.is_empty() {
+			return;
+		}
+
+		let sends = match self.post_batches(batch) {
+			Ok(sends) => sends,
+			Err(err) => {
+				warn!("failed to post batch: {}", err);
+				return;
+			}
+		};
+
+		for res in join_all(sends).await {
+			match res {
+				Ok(res) if res.status().is_success() => {
+					trace!(status = %res.status(), "posted batch");
+				}
+				Ok(res) => warn!(status = %res.status(), "target rejected batch"),
+				Err(err) => warn!("failed to post batch: {}", err),
+			}
+		}
+	}
+
+	/// Posts whatever is left in the batch buffer, stops new requests from being sent, and
+	/// resolves once all in-flight requests have completed.
+	pub async fn drain(&self) {
+		self.flush().await;
+		self.draining.store(true, Ordering::SeqCst);
+		loop {
+			let left = self.in_flight.load(Ordering::SeqCst);
+			if left == 0 {
+				break;
+			}
+
+			trace!("draining, {} requests still in flight", left);
+			async_std::task::sleep(Duration::from_millis(50)).await;
+		}
+
+		info!("client drained");
+	}
+
+	/// A `GET {base}/health` request, with the same headers and auth as events, and which times
+	/// out after `ACCORD_REQUEST_TIMEOUT_MS`.
+	fn health_request(&self) -> Result<Request<()>, Error> {
+		let req =
+			Request::get(format!("{}{}", self.base, Health.url())).timeout(self.request_timeout);
+		let req = self.prepare(req, &Health);
+		Ok(self.auth.apply(req, &[]).body(())?)
+	}
+
+	/// Checks whether the target is up, by GETting `{base}/health`.
+	///
+	/// This bypasses the circuit breaker, rate limit and retries, but feeds its result back into
+	/// the breaker: a healthy target closes it, and an unhealthy one counts as a failure. A request
+	/// which times out counts as unreachable.
+	pub async fn health_check(&self) -> HealthStatus {
+		let start = Instant::now();
+		let res = match self.health_request() {
+			Ok(req) => self.client.send_async(req).await.ok(),
+			Err(_) => None,
+		};
+		let status = HealthStatus {
+			reachable: res.is_some(),
+			latency_ms: start.elapsed().as_millis() as u64,
+			status_code: res.map_or(0, |r| r.status().as_u16()),
+		};
+
+		self.breaker.record(status.is_healthy());
+		status
+	}
+
+	/// Opens `ACCORD_TARGET_POOL_MIN_IDLE` connections to the target at once, with concurrent
+	/// `GET /health` requests, so they're in the pool ready for events.
+	///
+	/// curl has no minimum pool size, so this is how the minimum is kept.
+	pub async fn warm_pool(&self) {
+		let requests = (0..self.pool_min_idle).filter_map(|_| {
+			let req = self.health_request().ok()?;
+			Some(self.client.send_async(req))
+		});
+		let opened = join_all(requests)
+			.await
+			.into_iter()
+			.filter(Result::is_ok)
+			.count();
+		trace!(opened, "warmed connection pool to target");
+	}
+
+	/// Runs [`health_check`](Self::health_check) every `ACCORD_HEALTH_CHECK_INTERVAL_SECS`, forever,
+	/// keeping the connection pool warm alongside it.
+	///
+	/// Does nothing if the interval is zero, as warming the pool also sends health checks.
+	pub async fn monitor_health(self: Arc<Self>) {
+		if self.health_check_interval == Duration::from_secs(0) {
+			return;
+		}
+
+		self.warm_pool().await;
+
+		loop {
+			async_std::task::sleep(self.health_check_interval).await;
+			if self.pool_min_idle > 1 {
+				self.warm_pool().await;
+			}
+
+			let status = self.health_check().await;
+			if status.is_healthy() {
+				debug!(latency_ms = status.latency_ms, "target is healthy");
+			} else {
+				warn!(
+					reachable = status.reachable,
+					status_code = status.status_code,
+					latency_ms = status.latency_ms,
+					"target is degraded"
+				);
+			}
+		}
+	}
+
+	/// Tells the target about an error while handling an event, if `ACCORD_TARGET_ERROR_PATH` is set.
+	///
+	/// Failing to report is only logged, so this never errors itself.
+	pub async fn report_error(&self, err: &Error, event_type: &str) {
+		let path = match self.error_path {
+			Some(ref path) => path.clone(),
+			None => return,
+		};
+
+		let report = ErrorReport {
+			path,
+			error_type: err.kind().to_string(),
+			message: err.to_string(),
+			event_type: event_type.to_string(),
+			timestamp: chrono::Utc::now().to_rfc3339(),
+		};
+
+		trace!("reporting error to target: {:?}", report);
+		match self.post(report) {
+			Ok(res) => match res.await {
+				Ok(res) if !res.status().is_success() => {
+					warn!("target rejected error report: {:?}", res.status())
+				}
+				Ok(_) => {}
+				Err(err) => warn!("failed to report error to target: {}", err),
+			},
+			Err(err) => warn!("failed to report error to target: {}", err),
+		}
+	}
+
+	/// The target base URL for events from a channel, from `ACCORD_CHANNEL_ROUTES` or `ACCORD_TARGET`.
+	pub fn route_for(&self, channel_id: u64) -> &str {
+		self.channel_routes
+			.get(&channel_id)
+			.map_or(&self.base, |base| base)
+	}
+
+	fn base_for<S: Sendable>(&self, payload: &S) -> &str {
+		match payload.channel_id() {
+			Some(channel_id) => self.route_for(channel_id),
+			None => &self.base,
+		}
+	}
+
+	/// Sets the headers common to all requests, then the payload's own.
+	fn prepare<S: Sendable>(&self, mut req: RequestBuilder, payload: &S) -> RequestBuilder {
+		req = req
+			.header("content-type", "application/json")
+			.header("accord-payload-version", self.payload_version);
+
+		if let Some(key) = payload.idempotency_key() {
+			req = req.header("idempotency-key", key);
+		}
+
+		if let (Some(header), Some(server_id)) = (&self.guild_features_header, payload.server_id())
+		{
+			if let Some(guild) = self.cache.get().and_then(|c| c.guild(GuildId(server_id))) {
+				req = req.header(header.as_str(), guild.features.join(","));
+			}
+		}
+
+		if let (true, Some(server_id)) = (self.guild_shard_routing, payload.server_id()) {
+			match self.shard_count.load(Ordering::Relaxed) {
+				0 => {}
+				count => req = req.header("accord-responsible-shard", (server_id >> 22) % count),
+			}
+		}
+
+		payload.customise(req)
+	}
+
+	fn serialise<S: Sendable>(&self, payload: &S) -> serde_json::Result<Vec<u8>> {
+		let mut value = serde_json::to_value(payload)?;
+		if let Value::Object(ref mut map) = value {
+			map.insert("v".into(), self.payload_version.into());
+		}
+
+		if self.embed_urls_only {
+			embeds_to_urls(&mut value);
+		} else if self.embed_forwarding != EmbedForwarding::Full {
+			trim_embeds(&mut value, self.embed_forwarding);
+		}
+		if !self.serialize_nulls {
+			strip_nulls(&mut value);
+		}
+		serde_json::to_vec(&value)
+	}
+
+	/// Sends a request, retrying connection errors and 5xx statuses with exponential backoff.
+	fn send<B: Into<Body> + Clone + Send + 'static>(
+		&self,
+		req: Request<B>,
+	) -> Result<TargetResponse<'_>, Error> {
+		if self.draining.load(Ordering::SeqCst) {
+			warn!(to = %req.uri(), "client is draining, discarding request");
+			return Err(Error::Draining);
+		}
+
+		if !self.breaker.allow() {
+			warn!(to = %req.uri(), "circuit breaker is open, discarding request");
+			return Err(Error::CircuitOpen);
+		}
+
+		if self.tls_skip_verify {
+			warn!(to = %req.uri(), "sending request without TLS certificate verification");
+		}
+
+		let (parts, body) = req.into_parts();
+		let in_flight = InFlight::new(&self.in_flight);
+		Ok(async move {
+			let _in_flight = in_flight;
+			let mut attempt = 0;
+			loop {
+				let mut req = Request::new(body.clone().into());
+				*req.method_mut() = parts.method.clone();
+				*req.uri_mut() = parts.uri.clone();
+				*req.version_mut() = parts.version;
+				*req.headers_mut() = parts.headers.clone();
+
+				if let Some(ref limiter) = self.limiter {
+					limiter.acquire().await;
+				}
+
+				let started = Instant::now();
+				let res = match timeout(self.request_timeout, self.client.send_async(req)).await {
+					Ok(res) => res.map_err(Error::from),
+					Err(_) => {
+						warn!(
+							to = %parts.uri,
+							elapsed = ?started.elapsed(),
+							"request to target timed out"
+						);
+						Err(Error::Timeout)
+					}
+				};
+				let ok = match res {
+					Ok(ref r) => !r.status().is_server_error(),
+					Err(_) => false,
+				};
+				self.breaker.record(ok);
+
+				if ok || self.max_retries == 0 || self.breaker.state() == CircuitState::Open {
+					return res;
+				}
+
+				if attempt >= self.max_retries {
+					error!(
+						to = %parts.uri,
+						attempts = attempt + 1,
+						status = ?res.as_ref().ok().map(|r| r.status()),
+						"request to target failed, giving up"
+					);
+					return res;
+				}
+
+				if !self.retry_budget.take() {
+					error!(
+						to = %parts.uri,
+						attempts = attempt + 1,
+						"request to target failed, and the retry budget for this minute is used up, giving up"
+					);
+					return res;
+				}
+
+				let delay = self.retry_base * 2_u32.saturating_pow(attempt);
+				warn!(
+					to = %parts.uri,
+					attempt = attempt + 1,
+					"request to target failed, retrying in {:?}", delay
+				);
+				async_std::task::sleep(delay).await;
+				attempt += 1;
+			}
+		}
+		.boxed())
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitState {
+	/// Requests flow normally.
+	Closed,
+	/// Requests are discarded until the reset time has elapsed.
+	Open,
+	/// A single probe request is let through to test the target.
+	HalfOpen,
+}
+
+/// Stops sending requests to a target which keeps failing.
+///
+/// After `threshold` consecutive failures (connection errors or 5xx statuses), the breaker opens
+/// and all requests are discarded. Once `reset` has elapsed, one probe request is allowed through:
+/// if it succeeds the breaker closes again, otherwise it reopens for another `reset` period.
+///
+/// A threshold of zero disables the breaker entirely.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+	threshold: u32,
+	reset: Duration,
+	inner: Mutex<BreakerInner>,
+}
+
+#[derive(Debug)]
+struct BreakerInner {
+	state: CircuitState,
+	failures: u32,
+	/// When the breaker opened, or when it last let a probe through.
+	opened_at: Instant,
+}
+
+impl Default for CircuitBreaker {
+	fn default() -> Self {
+		Self::new(10, Duration::from_secs(30))
+	}
+}
+
+impl CircuitBreaker {
+	pub fn new(threshold: u32, reset: Duration) -> Self {
+		Self {
+			threshold,
+			reset,
+			inner: Mutex::new(BreakerInner {
+				state: CircuitState::Closed,
+				failures: 0,
+				opened_at: Instant::now(),
+			}),
+		}
+	}
+
+	pub fn state(&self) -> CircuitState {
+		self.inner.lock().unwrap().state
+	}
+
+	/// Whether a request may be sent now.
+	///
+	/// A probe which hasn't been recorded within the reset time (say, because it was dropped) is
+	/// given up on, and another one is let through.
+	pub fn allow(&self) -> bool {
+		if self.threshold == 0 {
+			return true;
+		}
+
+		let mut inner = self.inner.lock().unwrap();
+		match inner.state {
+			CircuitState::Closed => true,
+			CircuitState::Open | CircuitState::HalfOpen
+				if inner.opened_at.elapsed() >= self.reset =>
+			{
+				info!("circuit breaker is half-open, probing target");
+				inner.state = CircuitState::HalfOpen;
+				inner.opened_at = Instant::now();
+				true
+			}
+			CircuitState::Open | CircuitState::HalfOpen => false,
+		}
+	}
+
+	/// Records the outcome of a request.
+	pub fn record(&self, success: bool) {
+		if self.threshold == 0 {
+			return;
+		}
+
+		let mut inner = self.inner.lock().unwrap();
+		if success {
+			if inner.state != CircuitState::Closed {
+				info!("target has recovered, closing circuit breaker");
+			}
+
+			inner.state = CircuitState::Closed;
+			inner.failures = 0;
+			return;
+		}
+
+		inner.failures = inner.failures.saturating_add(1);
+		if inner.state == CircuitState::HalfOpen || inner.failures >= self.threshold {
+			if inner.state != CircuitState::Open {
+				warn!(
+					failures = inner.failures,
+					"target keeps failing, opening circuit breaker for {:?}", self.reset
+				);
+			}
+
+			inner.state = CircuitState::Open;
+			inner.opened_at = Instant::now();
+		}
+	}
+}
+
+/// The result of [`Client::health_check`].
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct HealthStatus {
+	/// Whether the target responded at all.
+	pub reachable: bool,
+	pub latency_ms: u64,
+	/// Zero if the target was unreachable.
+	pub status_code: u16,
+}
+
+impl HealthStatus {
+	/// Reachable, and not responding with a 5xx or a 4xx other than 404 (so a target rejecting
+	/// Accord's credentials is degraded, but one without a `/health` route isn't).
+	pub fn is_healthy(&self) -> bool {
+		self.reachable && (self.status_code < 400 || self.status_code == 404)
+	}
+}
+
+/// How much of message embeds to include in payloads, from `ACCORD_EMBED_FORWARDING`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmbedForwarding {
+	/// Leave out `embeds` entirely.
+	None,
+	/// Only each embed's `url` and `type`.
+	Urls,
+	/// Everything.
+	Full,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentHash {
+	Sha256,
+	Md5,
+}
+
+/// Decides whether an event is forwarded to the target.
+///
+/// Filters added to a [`Client`] are applied in order, and an event is only forwarded if all of
+/// them allow it. `event_type` is the `event_type` of the payload the event would become, e.g.
+/// `server_message`, and `user_id` is the user who caused the event, or zero if unknown.
+pub trait EventFilter: Send + Sync {
+	fn allow(
+		&self,
+		event_type: &str,
+		guild_id: Option<u64>,
+		channel_id: Option<u64>,
+		user_id: u64,
+	) -> bool;
+}
+
+/// Allows or denies events from a set of servers. Events outside servers always pass.
+#[derive(Clone, Debug)]
+pub struct GuildFilter {
+	pub ids: HashSet<u64>,
+	pub deny: bool,
+}
+
+impl EventFilter for GuildFilter {
+	fn allow(&self, _: &str, guild_id: Option<u64>, _: Option<u64>, _: u64) -> bool {
+		guild_id.is_none_or(|id| self.ids.contains(&id) != self.deny)
+	}
+}
+
+/// Allows or denies events from a set of channels. Events outside channels always pass.
+#[derive(Clone, Debug)]
+pub struct ChannelFilter {
+	pub ids: HashSet<u64>,
+	pub deny: bool,
+}
+
+impl EventFilter for ChannelFilter {
+	fn allow(&self, _: &str, _: Option<u64>, channel_id: Option<u64>, _: u64) -> bool {
+		channel_id.is_none_or(|id| self.ids.contains(&id) != self.deny)
+	}
+}
+
+/// Allows or denies events caused by a set of users.
+#[derive(Clone, Debug)]
+pub struct UserFilter {
+	pub ids: HashSet<u64>,
+	pub deny: bool,
+}
+
+impl EventFilter for UserFilter {
+	fn allow(&self, _: &str, _: Option<u64>, _: Option<u64>, user_id: u64) -> bool {
+		self.ids.contains(&user_id) != self.deny
+	}
+}
+
+/// Denies events caused by the bot itself, given its user ID.
+#[derive(Clone, Copy, Debug)]
+pub struct BotFilter(pub u64);
+
+impl EventFilter for BotFilter {
+	fn allow(&self, _: &str, _: Option<u64>, _: Option<u64>, user_id: u64) -> bool {
+		user_id != self.0
+	}
+}
+
+/// Combines filters, allowing events if all of them (`And`) or any of them (`Or`) do.
+pub enum CompositeFilter {
+	And(Vec<Box<dyn EventFilter>>),
+	Or(Vec<Box<dyn EventFilter>>),
+}
+
+impl EventFilter for CompositeFilter {
+	fn allow(
+		&self,
+		event_type: &str,
+		guild_id: Option<u64>,
+		channel_id: Option<u64>,
+		user_id: u64,
+	) -> bool {
+		match self {
+			Self::And(filters) => filters
+				.iter()
+				.all(|f| f.allow(event_type, guild_id, channel_id, user_id)),
+			Self::Or(filters) => filters
+				.iter()
+				.any(|f| f.allow(event_type, guild_id, channel_id, user_id)),
+		}
+	}
+}
+
+/// A payload which couldn't be delivered to the target, as written to `ACCORD_DEAD_LETTER_FILE`.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeadLetter {
+	/// The full URL the payload was posted to.
+	pub url: String,
+	/// RFC 3339 timestamp.
+	pub timestamp: String,
+	pub error: String,
+	pub payload: Value,
+}
+
+impl DeadLetter {
+	fn new(url: String, body: &[u8], error: String) -> Self {
+		Self {
+			url,
+			timestamp: chrono::Utc::now().to_rfc3339(),
+			error,
+			payload: serde_json::from_slice(body).unwrap_or(Value::Null),
+		}
+	}
+}
+
+/// Appends dead letters to a file, one JSON object per line.
+struct DeadLetters {
+	path: String,
+	file: async_std::sync::Mutex<async_std::fs::File>,
+}
+
+impl DeadLetters {
+	fn open(path: &str) -> std::io::Result<Self> {
+		let file = std::fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)?;
+		Ok(Self {
+			path: path.into(),
+			file: async_std::sync::Mutex::new(file.into()),
+		})
+	}
+
+	async fn write(&self, letter: &DeadLetter) {
+		let mut line = match serde_json::to_vec(letter) {
+			Ok(line) => line,
+			Err(err) => {
+				error!("failed to serialise dead letter: {}", err);
+				return;
+			}
+		};
+		line.push(b'\n');
+
+		let mut file = self.file.lock().await;
+		match file.write_all(&line).await {
+			Ok(()) => warn!(
+				to = letter.url.as_str(),
+				"wrote undelivered payload to {}", self.path
+			),
+			Err(err) => error!("failed to write dead letter to {}: {}", self.path, err),
+		}
+		if let Err(err) = file.flush().await {
+			error!("failed to write dead letter to {}: {}", self.path, err);
+		}
+	}
+}
+
+/// Counts a request (or anything else) as in flight for as long as it is alive.
+pub(crate) struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+	pub(crate) fn new(counter: &Arc<AtomicUsize>) -> Self {
+		counter.fetch_add(1, Ordering::SeqCst);
+		Self(counter.clone())
+	}
+}
+
+impl Drop for InFlight {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+/// A way of authenticating requests to the target, as part of an [`AuthChain`].
+pub trait AuthStrategy: Send + Sync {
+	/// Adds this strategy's headers to a request, given its final body (empty for GETs).
+	fn apply(&self, req: RequestBuilder, body: &[u8]) -> RequestBuilder;
+}
+
+/// Authentication strategies applied in order to every request, from `ACCORD_AUTH_CHAIN`.
+///
+/// Parses from a JSON array of strategies, each an object with a `type` of `hmac_sha256` (with
+/// `secret` and optional `header`), `bearer` (with `token`), `api_key` (with `key` and optional
+/// `header`), `basic` (with `username` and `password`), or `none`.
+#[derive(Default)]
+pub struct AuthChain(pub Vec<Box<dyn AuthStrategy>>);
+
+impl AuthChain {
+	pub fn push(&mut self, strategy: impl AuthStrategy + 'static) {
+		self.0.push(Box::new(strategy));
+	}
+
+	pub fn apply(&self, req: RequestBuilder, body: &[u8]) -> RequestBuilder {
+		self.0
+			.iter()
+			.fold(req, |req, strategy| strategy.apply(req, body))
+	}
+}
+
+impl FromStr for AuthChain {
+	type Err = serde_json::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let configs: Vec<AuthConfig> = serde_json::from_str(s)?;
+		Ok(Self(
+			configs
+				.into_iter()
+				.map(|config| -> Box<dyn AuthStrategy> {
+					match config {
+						AuthConfig::HmacSha256 { secret, header } => {
+							Box::new(HmacSha256Auth { secret, header })
+						}
+						AuthConfig::Bearer { token } => Box::new(BearerTokenAuth { token }),
+						AuthConfig::ApiKey { key, header } => Box::new(ApiKeyAuth { key, header }),
+						AuthConfig::Basic { username, password } => {
+							Box::new(BasicAuth { username, password })
+						}
+						AuthConfig::None => Box::new(NoAuth),
+					}
+				})
+				.collect(),
+		))
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AuthConfig {
+	HmacSha256 {
+		secret: String,
+		#[serde(default = "signature_header")]
+		header: String,
+	},
+	Bearer {
+		token: String,
+	},
+	ApiKey {
+		key: String,
+		#[serde(default = "api_key_header")]
+		header: String,
+	},
+	Basic {
+		username: String,
+		password: String,
+	},
+	None,
+}
+
+fn signature_header() -> String {
+	String::from("accord-signature")
+}
+
+fn api_key_header() -> String {
+	String::from("x-api-key")
+}
+
+/// Signs the request body with HMAC-SHA256, as a hex digest in a header.
+pub struct HmacSha256Auth {
+	pub secret: String,
+	pub header: String,
+}
+
+impl AuthStrategy for HmacSha256Auth {
+	fn apply(&self, req: RequestBuilder, body: &[u8]) -> RequestBuilder {
+		let mut mac = Hmac::<Sha256>::new_varkey(self.secret.as_bytes())
+			.expect("hmac accepts keys of any length");
+		mac.update(body);
+		req.header(
+			self.header.as_str(),
+			format!("{:x}", mac.finalize().into_bytes()),
+		)
+	}
+}
+
+/// Sends `authorization: Bearer {token}`.
+pub struct BearerTokenAuth {
+	pub token: String,
+}
+
+impl AuthStrategy for BearerTokenAuth {
+	fn apply(&self, req: RequestBuilder, _body: &[u8]) -> RequestBuilder {
+		req.header("authorization", format!("Bearer {}", self.token))
+	}
+}
+
+/// Sends a static key in a header.
+pub struct ApiKeyAuth {
+	pub key: String,
+	pub header: String,
+}
+
+impl AuthStrategy for ApiKeyAuth {
+	fn apply(&self, req: RequestBuilder, _body: &[u8]) -> RequestBuilder {
+		req.header(self.header.as_str(), self.key.as_str())
+	}
+}
+
+/// HTTP basic authentication.
+pub struct BasicAuth {
+	pub username: String,
+	pub password: String,
+}
+
+impl AuthStrategy for BasicAuth {
+	fn apply(&self, req: RequestBuilder, _body: &[u8]) -> RequestBuilder {
+		req.authentication(Authentication::basic())
+			.credentials(Credentials::new(
+				self.username.as_str(),
+				self.password.as_str(),
+			))
+	}
+}
+
+/// Does nothing, for explicitness.
+pub struct NoAuth;
+
+impl AuthStrategy for NoAuth {
+	fn apply(&self, req: RequestBuilder, _body: &[u8]) -> RequestBuilder {
+		req
+	}
+}
+
+/// Limits how many retries are made across all requests in each minute, so that many requests
+/// failing at once don't turn into a storm of retries.
+///
+/// A limit of zero disables the budget entirely.
+#[derive(Debug)]
+pub struct RetryBudget {
+	limit: u64,
+	inner: Mutex<(Instant, u64)>,
+}
+
+impl RetryBudget {
+	pub fn new(limit: u64) -> Self {
+		Self {
+			limit,
+			inner: Mutex::new((Instant::now(), 0)),
+		}
+	}
+
+	/// Takes one retry from the budget, if there's any left in the current minute.
+	pub fn take(&self) -> bool {
+		if self.limit == 0 {
+			return true;
+		}
+
+		let mut inner = self.inner.lock().unwrap();
+		let (ref mut window, ref mut used) = *inner;
+		if window.elapsed() >= Duration::from_secs(60) {
+			*window = Instant::now();
+			*used = 0;
+		}
+
+		if *used >= self.limit {
+			return false;
+		}
+
+		*used += 1;
+		true
+	}
+}
+
+/// Limits the rate of requests to the target with a token bucket.
+///
+/// The bucket holds up to `burst` tokens and refills at `rate` tokens per second; each request
+/// takes one token, waiting for it if the bucket is empty.
+#[derive(Debug)]
+pub struct RateLimiter {
+	rate: f64,
+	burst: f64,
+	inner: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+	/// # Panics
+	///
+	/// Will panic if `rate` isn't positive.
+	pub fn new(rate: f64, burst: f64) -> Self {
+		assert!(rate > 0.0, "FATAL: bad value: ACCORD_RATE_LIMIT_RPS");
+		let burst = burst.max(1.0);
+		Self {
+			rate,
+			burst,
+			inner: Mutex::new((burst, Instant::now())),
+		}
+	}
+
+	/// Waits until a request may be sent.
+	pub async fn acquire(&self) {
+		loop {
+			let wait = {
+				let mut inner = self.inner.lock().unwrap();
+				let (ref mut tokens, ref mut refilled_at) = *inner;
+				*tokens =
+					(*tokens + refilled_at.elapsed().as_secs_f64() * self.rate).min(self.burst);
+				*refilled_at = Instant::now();
+
+				if *tokens >= 1.0 {
+					*tokens -= 1.0;
+					return;
+				}
+
+				Duration::from_secs_f64((1.0 - *tokens) / self.rate)
+			};
+
+			trace!("rate limited, waiting {:?}", wait);
+			async_std::task::sleep(wait).await;
+		}
+	}
+}
+
+pub trait Sendable: Serialize {
+	fn url(&self) -> String;
+
+	/// The label for this payload in metrics: by default, the name of its type without its path
+	/// or generic parameters, e.g. `Command` for a `Command<ServerMessage>`.
+	fn event_type(&self) -> &'static str {
+		let name = std::any::type_name::<Self>();
+		let name = name.split('<').next().unwrap_or(name);
+		name.rsplit("::").next().unwrap_or(name)
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		req
+	}
+
+	/// A key identifying this payload, so the target can safely ignore retries of it.
+	fn idempotency_key(&self) -> Option<String> {
+		None
+	}
+
+	/// The server this payload relates to, if any.
+	fn server_id(&self) -> Option<u64> {
+		None
+	}
+
+	/// The channel this payload relates to, if any.
+	fn channel_id(&self) -> Option<u64> {
+		None
+	}
+
+	/// A key grouping this payload with similar ones, so they're batched together.
+	///
+	/// With `ACCORD_BATCH_MAX`, payloads with a key are buffered and posted in batches, and
+	/// others are posted immediately.
+	fn batch_key(&self) -> Option<String> {
+		None
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Connecting;
+
+impl Sendable for Connecting {
+	fn url(&self) -> String {
+		"/discord/connecting".to_string()
+	}
+}
+
+/// The request [`Client::health_check`] sends, which has no body.
+#[derive(Clone, Debug, Serialize)]
+pub struct Health;
+
+impl Sendable for Health {
+	fn url(&self) -> String {
+		"/health".to_string()
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Activity {
+	Playing { name: String },
+	Streaming { name: String },
+	Listening { name: String },
+	Watching { name: String },
+	Custom { name: String },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Presence {
+	pub afk: Option<bool>,
+	pub activity: Option<Activity>,
+	pub since: Option<u64>,
+	pub status: Option<Status>,
+}
+
+/// A gateway event Accord doesn't otherwise handle, sent when
+/// `ACCORD_FORWARD_RAW_GATEWAY_EVENTS=1` is set.
+#[derive(Clone, Debug, Serialize)]
+pub struct RawEvent {
+	/// The gateway dispatch name, like `CHANNEL_PINS_UPDATE`.
+	pub event_type: String,
+	/// The event's payload, as twilight serialises it.
+	pub raw: serde_json::Value,
+}
+
+impl Sendable for RawEvent {
+	fn url(&self) -> String {
+		format!("/event/raw/{}", self.event_type)
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Connected {
+	pub shard: u64,
+}
+
+impl Sendable for Connected {
+	fn url(&self) -> String {
+		"/discord/connected".to_string()
+	}
+}
+
+/// Gateway heartbeat timing, sent on every heartbeat acknowledgement when
+/// `ACCORD_EMIT_HEARTBEAT_EVENTS=1` is set.
+#[derive(Clone, Debug, Serialize)]
+pub struct HeartbeatAck {
+	pub shard_id: u64,
+	/// Round trip time of the most recent heartbeat, if one has been measured.
+	pub latency_ms: Option<u64>,
+	pub sequence: u64,
+}
+
+impl Sendable for HeartbeatAck {
+	fn url(&self) -> String {
+		format!("/shard/{}/heartbeat", self.shard_id)
+	}
+}
+
+/// A payload waiting in the batch buffer, with `ACCORD_BATCH_MAX`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(transparent)]
+struct Buffered {
+	#[serde(skip)]
+	key: Option<String>,
+	value: Value,
+}
+
+impl Sendable for Buffered {
+	fn url(&self) -> String {
+		"/batch".to_string()
+	}
+
+	fn batch_key(&self) -> Option<String> {
+		self.key.clone()
+	}
+}
+
+/// Several events sent together, as a JSON array.
+#[derive(Clone, Debug, Serialize)]
+pub struct Batch(pub Vec<Value>);
+
+impl Sendable for Batch {
+	fn url(&self) -> String {
+		"/batch".to_string()
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		req.header("accord-batch-size", self.0.len())
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ShardCount {
+	/// The path to post to, from `ACCORD_SHARD_COUNT_REPORT_PATH`.
+	#[serde(skip)]
+	pub path: String,
+	pub shard_count: u64,
+}
+
+impl Sendable for ShardCount {
+	fn url(&self) -> String {
+		self.path.clone()
+	}
+}
+
+/// An error Accord hit while handling an event, sent when `ACCORD_TARGET_ERROR_PATH` is set.
+#[derive(Clone, Debug, Serialize)]
+pub struct ErrorReport {
+	/// The path to post to, from `ACCORD_TARGET_ERROR_PATH`.
+	#[serde(skip)]
+	pub path: String,
+	/// Which kind of error, like `timeout` or `discord`.
+	pub error_type: String,
+	pub message: String,
+	/// The gateway event being handled, like `MESSAGE_CREATE`.
+	pub event_type: String,
+	/// RFC 3339 timestamp.
+	pub timestamp: String,
+}
+
+impl Sendable for ErrorReport {
+	fn url(&self) -> String {
+		self.path.clone()
+	}
+}
+
+/// Startup information, logged and optionally sent to the target once the
+/// first shard is ready.
+#[derive(Clone, Debug, Serialize)]
+pub struct Telemetry {
+	/// Unix timestamp in seconds.
+	pub started_at: u64,
+	pub bot_id: u64,
+	pub session_id: String,
+	pub shard_count: u64,
+	pub intents: String,
+	/// The target base URL, without any credentials or query string.
+	pub target: String,
+}
+
+impl Sendable for Telemetry {
+	fn url(&self) -> String {
+		"/telemetry".to_string()
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct User {
+	pub id: u64,
+	pub name: String,
+	#[serde(default)]
+	pub discriminator: String,
+	#[serde(default)]
+	pub bot: bool,
+	/// Full CDN URL of the avatar, or of the default avatar if the user hasn't set one.
+	#[serde(default)]
+	pub avatar_url: Option<String>,
+}
+
+impl From<&DisUser> for User {
+	fn from(dis: &DisUser) -> Self {
+		Self {
+			id: dis.id.0,
+			discriminator: dis.discriminator.clone(),
+			name: dis.name.clone(),
+			bot: dis.bot,
+			avatar_url: Some(avatar_url(
+				dis.id.0,
+				dis.avatar.as_deref(),
+				&dis.discriminator,
+			)),
+		}
+	}
+}
+
+impl From<&CurrentUser> for User {
+	fn from(dis: &CurrentUser) -> Self {
+		Self {
+			id: dis.id.0,
+			discriminator: dis.discriminator.clone(),
+			name: dis.name.clone(),
+			bot: dis.bot,
+			avatar_url: Some(avatar_url(
+				dis.id.0,
+				dis.avatar.as_deref(),
+				&dis.discriminator,
+			)),
+		}
+	}
+}
+
+impl From<&User> for DisUser {
+	fn from(rac: &User) -> Self {
+		Self {
+			id: UserId(rac.id),
+			discriminator: rac.discriminator.clone(),
+			name: rac.name.clone(),
+			bot: rac.bot,
+			avatar: rac.avatar_url.as_deref().and_then(|url| {
+				url.strip_prefix(&format!("{}/avatars/{}/", DISCORD_CDN, rac.id))?
+					.strip_suffix(".png")
+					.map(String::from)
+			}),
+
+			email: Default::default(),
+			flags: Default::default(),
+			locale: Default::default(),
+			mfa_enabled: Default::default(),
+			premium_type: Default::default(),
+			public_flags: Default::default(),
+			system: Default::default(),
+			verified: Default::default(),
+		}
+	}
+}
+
+const DISCORD_CDN: &str = "https://cdn.discordapp.com";
+
+/// The CDN URL of a user's avatar, given its hash, or of their default avatar without one.
+fn avatar_url(id: u64, avatar: Option<&str>, discriminator: &str) -> String {
+	match avatar {
+		Some(hash) => format!("{}/avatars/{}/{}.png", DISCORD_CDN, id, hash),
+		None => format!(
+			"{}/embed/avatars/{}.png",
+			DISCORD_CDN,
+			discriminator.parse::<u16>().unwrap_or_default() % 5
+		),
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BotUpdate(pub User);
+
+impl Sendable for BotUpdate {
+	fn url(&self) -> String {
+		"/bot/update".to_string()
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		req.header("accord-user-id", self.0.id)
+			.header("accord-user-name", &escape(&self.0.name))
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Member {
+	pub user: User,
+	pub server_id: u64,
+	#[serde(default)]
+	pub roles: Option<Vec<u64>>,
+	#[serde(default)]
+	pub pseudonym: Option<String>,
+	#[serde(default)]
+	pub joined_at: Option<String>,
+}
+
+impl From<&DisMessage> for Member {
+	fn from(dis: &DisMessage) -> Self {
+		Self {
+			user: User::from(&dis.author),
+			server_id: dis.guild_id.map(|g| g.0).unwrap_or_default(),
+			roles: dis
+				.member
+				.as_ref()
+				.map(|mem| mem.roles.iter().map(|role| role.0).collect()),
+			pseudonym: dis.member.as_ref().and_then(|mem| mem.nick.clone()),
+			joined_at: dis.member.as_ref().and_then(|mem| mem.joined_at.clone()),
+		}
+	}
+}
+
+impl From<&DisMember> for Member {
+	fn from(dis: &DisMember) -> Self {
+		Self {
+			user: User::from(&dis.user),
+			server_id: dis.guild_id.0,
+			roles: Some(dis.roles.iter().map(|role| role.0).collect()),
+			pseudonym: dis.nick.clone(),
+			joined_at: dis.joined_at.clone(),
+		}
+	}
+}
+
+impl From<&Member> for PartialMember {
+	fn from(rac: &Member) -> Self {
+		Self {
+			roles: rac
+				.roles
+				.as_ref()
+				.map(|v| v.iter().map(|r| RoleId(*r)).collect())
+				.unwrap_or_default(),
+			nick: rac.pseudonym.clone(),
+
+			joined_at: rac.joined_at.clone(),
+
+			deaf: Default::default(),
+			mute: Default::default(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerJoin(pub Member);
+
+impl Sendable for ServerJoin {
+	fn url(&self) -> String {
+		format!("/server/{}/join/{}", self.0.server_id, self.0.user.id)
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		Some(self.0.server_id)
+	}
+
+	fn customise(&self, mut req: RequestBuilder) -> RequestBuilder {
+		req = req
+			.header("accord-server-id", self.0.server_id)
+			.header("accord-member-id", self.0.user.id)
+			.header("accord-member-name", &escape(&self.0.user.name));
+
+		if let Some(ref pseud) = self.0.pseudonym {
+			req = req.header("accord-member-pseudonym", pseud);
+		}
+
+		if let Some(ref roles) = self.0.roles {
+			for role in roles {
+				req = req.header("accord-member-role-ids", *role);
+			}
+		}
+
+		req
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerLeave {
+	pub server_id: u64,
+	pub user: User,
+}
+
+impl Sendable for ServerLeave {
+	fn url(&self) -> String {
+		format!("/server/{}/leave/{}", self.server_id, self.user.id)
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		Some(self.server_id)
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		req.header("accord-server-id", self.server_id)
+			.header("accord-member-id", self.user.id)
+			.header("accord-member-name", &escape(&self.user.name))
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerBanAdd {
+	pub server_id: u64,
+	pub user: User,
+}
+
+impl Sendable for ServerBanAdd {
+	fn url(&self) -> String {
+		format!("/server/{}/ban/add", self.server_id)
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		Some(self.server_id)
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		req.header("accord-server-id", self.server_id)
+			.header("accord-member-id", self.user.id)
+			.header("accord-member-name", &escape(&self.user.name))
+	}
+}
+
+impl From<&BanAdd> for ServerBanAdd {
+	fn from(dis: &BanAdd) -> Self {
+		Self {
+			server_id: dis.guild_id.0,
+			user: User::from(&dis.user),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerBanRemove {
+	pub server_id: u64,
+	pub user: User,
+}
+
+impl Sendable for ServerBanRemove {
+	fn url(&self) -> String {
+		format!("/server/{}/ban/remove", self.server_id)
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		Some(self.server_id)
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		req.header("accord-server-id", self.server_id)
+			.header("accord-member-id", self.user.id)
+			.header("accord-member-name", &escape(&self.user.name))
+	}
+}
+
+impl From<&BanRemove> for ServerBanRemove {
+	fn from(dis: &BanRemove) -> Self {
+		Self {
+			server_id: dis.guild_id.0,
+			user: User::from(&dis.user),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MembersChunk {
+	pub server_id: u64,
+	pub chunk_index: u32,
+	pub chunk_count: u32,
+	pub members: Vec<Member>,
+}
+
+impl From<&MemberChunk> for MembersChunk {
+	fn from(dis: &MemberChunk) -> Self {
+		Self {
+			server_id: dis.guild_id.0,
+			chunk_index: dis.chunk_index,
+			chunk_count: dis.chunk_count,
+			members: dis.members.values().map(Member::from).collect(),
+		}
+	}
+}
+
+impl Sendable for MembersChunk {
+	fn url(&self) -> String {
+		format!("/server/{}/members/chunk", self.server_id)
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		Some(self.server_id)
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		req.header("accord-server-id", self.server_id)
+			.header("accord-chunk-index", self.chunk_index)
+			.header("accord-chunk-count", self.chunk_count)
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct VoiceServerUpdate {
+	pub server_id: u64,
+	/// The voice connection token, or `[REDACTED]` unless
+	/// `ACCORD_REDACT_VOICE_TOKENS=0` is set.
+	pub token: String,
+	pub endpoint: Option<String>,
+}
+
+impl Sendable for VoiceServerUpdate {
+	fn url(&self) -> String {
+		format!("/server/{}/voice/server", self.server_id)
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		Some(self.server_id)
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		req.header("accord-server-id", self.server_id)
+	}
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageFlag {
+	Crossposted,
+	IsCrosspost,
+	SuppressEmbeds,
+	SourceMessageDeleted,
+	Urgent,
+}
+
+impl MessageFlag {
+	pub fn from_discord(dis: DisMessageFlags) -> Vec<Self> {
+		let mut flags = Vec::with_capacity(5);
+		if dis.contains(DisMessageFlags::CROSSPOSTED) {
+			flags.push(Self::Crossposted);
+		}
+		if dis.contains(DisMessageFlags::IS_CROSSPOST) {
+			flags.push(Self::IsCrosspost);
+		}
+		if dis.contains(DisMessageFlags::SUPPRESS_EMBEDS) {
+			flags.push(Self::SuppressEmbeds);
+		}
+		if dis.contains(DisMessageFlags::SOURCE_MESSAGE_DELETED) {
+			flags.push(Self::SourceMessageDeleted);
+		}
+		if dis.contains(DisMessageFlags::URGENT) {
+			flags.push(Self::Urgent);
+		}
+		flags
+	}
+
+	pub fn to_discord(rac: &[Self]) -> Option<DisMessageFlags> {
+		if rac.is_empty() {
+			return None;
+		}
+
+		let mut flags = DisMessageFlags::empty();
+		if rac.contains(&MessageFlag::Crossposted) {
 			flags.insert(DisMessageFlags::CROSSPOSTED);
 		}
 		if rac.contains(&MessageFlag::IsCrosspost) {
@@ -365,9 +2896,10 @@ impl fmt::Display for MessageFlag {
 		)
 	}
 }
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum MessageType {
+	#[default]
 	Regular,
 	RecipientAdd,
 	RecipientRemove,
@@ -386,12 +2918,6 @@ pub enum MessageType {
 	Reply,
 }
 
-impl Default for MessageType {
-	fn default() -> Self {
-		Self::Regular
-	}
-}
-
 impl From<DisMessageType> for MessageType {
 	fn from(dis: DisMessageType) -> Self {
 		use MessageType::*;
@@ -439,12 +2965,268 @@ impl From<MessageType> for DisMessageType {
 	}
 }
 
+/// Periodically refreshed details of a server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServerMetadata {
+	pub name: String,
+	#[serde(default)]
+	pub member_count: Option<u64>,
+	#[serde(default)]
+	pub features: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServerMessage {
+	/// Always `"server_message"`, to tell payloads apart without looking at the URL.
+	#[serde(skip_deserializing, default = "server_message_type")]
+	pub event_type: &'static str,
+	/// Always `false`.
+	#[serde(skip_deserializing)]
+	pub is_dm: bool,
+	pub id: u64,
+	pub server_id: u64,
+	/// Details of the server, with `ACCORD_GUILD_METADATA_REFRESH_INTERVAL_SECS`.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub server: Option<ServerMetadata>,
+	/// Member count of the server, when it's in the cache.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub server_member_count: Option<u32>,
+	pub channel_id: u64,
+	pub author: Member,
+	/// Unix timestamp in milliseconds from the message's snowflake, for ordering.
+	#[serde(default)]
+	pub position: Option<u64>,
+
+	pub timestamp_created: String,
+	#[serde(default)]
+	pub timestamp_edited: Option<String>,
+
+	#[serde(default)]
+	pub kind: MessageType,
+	/// Deprecated alias of `content_raw`, which it is always equal to, kept for existing targets.
+	pub content: String,
+	/// The content exactly as sent, with Discord's mention syntax.
+	#[serde(default)]
+	pub content_raw: String,
+	/// The content with mentions resolved to names, when resolution was performed.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub content_resolved: Option<String>,
+	/// Number of whitespace-separated words in the content.
+	#[serde(default)]
+	pub word_count: usize,
+	/// Number of users mentioned.
+	#[serde(default)]
+	pub mention_count: usize,
+	/// Whether the message mentions `@everyone` or `@here`.
+	#[serde(default)]
+	pub mention_everyone: bool,
+	/// Users mentioned, ordered by ID.
+	#[serde(default)]
+	pub user_mentions: Vec<User>,
+	#[serde(default)]
+	pub role_mention_ids: Vec<u64>,
+	/// Hex digest of the content, with `ACCORD_CONTENT_HASH_ALGORITHM`.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub content_hash: Option<String>,
+
+	#[serde(default)]
+	pub attachments: Vec<Attachment>,
+	#[serde(default)]
+	pub embeds: Vec<Embed>,
+	#[serde(default)]
+	pub reactions: Vec<MessageReaction>,
+
+	#[serde(default)]
+	pub application: Option<MessageApplication>,
+	#[serde(default)]
+	pub flags: Vec<MessageFlag>,
+}
+
+fn server_message_type() -> &'static str {
+	"server_message"
+}
+
+impl Sendable for ServerMessage {
+	fn url(&self) -> String {
+		format!(
+			"/server/{}/channel/{}/message",
+			self.server_id, self.channel_id
+		)
+	}
+
+	fn channel_id(&self) -> Option<u64> {
+		Some(self.channel_id)
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		Some(self.server_id)
+	}
+
+	fn idempotency_key(&self) -> Option<String> {
+		Some(format!("msg-{}", self.id))
+	}
+
+	fn batch_key(&self) -> Option<String> {
+		Some(format!("{}-{}", self.server_id, self.channel_id))
+	}
+
+	fn customise(&self, mut req: RequestBuilder) -> RequestBuilder {
+		req = req
+			.header("accord-message-id", self.id)
+			.header("accord-server-id", self.server_id)
+			.header("accord-channel-type", "text")
+			.header("accord-channel-id", self.channel_id)
+			.header(
+				"accord-author-type",
+				if self.author.user.bot { "bot" } else { "user" },
+			)
+			.header("accord-author-id", self.author.user.id)
+			.header("accord-author-name", &escape(&self.author.user.name))
+			.header("accord-content-length", self.content.len());
+
+		if let Some(ref hash) = self.content_hash {
+			req = req.header("accord-content-hash", hash);
+		}
+
+		if let Some(ref pseud) = self.author.pseudonym {
+			req = req.header("accord-author-pseudonym", escape(pseud));
+		}
+
+		if let Some(ref roles) = self.author.roles {
+			for role in roles {
+				req = req.header("accord-author-role-ids", *role);
+			}
+		}
+
+		for flag in &self.flags {
+			req = req.header("accord-message-flags", flag.to_string());
+		}
+
+		if !self.attachments.is_empty() {
+			req = req.header("accord-has-attachments", self.attachments.len());
+		}
+
+		if !self.embeds.is_empty() {
+			req = req.header("accord-has-embeds", self.embeds.len());
+		}
+
+		if !self.reactions.is_empty() {
+			req = req.header("accord-has-reactions", self.reactions.len());
+		}
+
+		req
+	}
+}
+
+impl From<&DisMessage> for ServerMessage {
+	/// Convert from a Discord message to a Raccord ServerMessage
+	///
+	/// # Panics
+	///
+	/// Will panic if there's no `guild_id`.
+	fn from(dis: &DisMessage) -> Self {
+		Self {
+			event_type: server_message_type(),
+			is_dm: false,
+			id: dis.id.0,
+			server_id: dis.guild_id.unwrap().0,
+			server: None,
+			server_member_count: None,
+			channel_id: dis.channel_id.0,
+			author: dis.into(),
+			position: Some((dis.id.0 >> 22) + 1_420_070_400_000),
+
+			timestamp_created: dis.timestamp.clone(),
+			timestamp_edited: dis.edited_timestamp.clone(),
+
+			kind: dis.kind.into(),
+			content: dis.content.clone(),
+			content_raw: dis.content.clone(),
+			content_resolved: None,
+			word_count: dis.content.split_whitespace().count(),
+			mention_count: dis.mentions.len(),
+			mention_everyone: dis.mention_everyone,
+			user_mentions: user_mentions(dis),
+			role_mention_ids: dis.mention_roles.iter().map(|id| id.0).collect(),
+			content_hash: None,
+
+			attachments: dis.attachments.clone(),
+			embeds: dis.embeds.clone(),
+			reactions: dis.reactions.clone(),
+
+			application: dis.application.clone(),
+			flags: dis.flags.map(MessageFlag::from_discord).unwrap_or_default(),
+		}
+	}
+}
+
+fn user_mentions(dis: &DisMessage) -> Vec<User> {
+	let mut users: Vec<User> = dis.mentions.values().map(User::from).collect();
+	users.sort_by_key(|user| user.id);
+	users
+}
+
+impl From<&ServerMessage> for DisMessage {
+	/// Convert from a Raccord ServerMessage to a Discord Message
+	fn from(rac: &ServerMessage) -> Self {
+		Self {
+			id: MessageId(rac.id),
+			guild_id: Some(GuildId(rac.server_id)),
+			channel_id: ChannelId(rac.channel_id),
+			author: (&rac.author.user).into(),
+			member: Some((&rac.author).into()),
+
+			timestamp: rac.timestamp_created.clone(),
+			edited_timestamp: rac.timestamp_edited.clone(),
+
+			kind: rac.kind.into(),
+			content: rac.content.clone(),
+
+			attachments: rac.attachments.clone(),
+			embeds: rac.embeds.clone(),
+			reactions: rac.reactions.clone(),
+
+			application: rac.application.clone(),
+			flags: MessageFlag::to_discord(&rac.flags),
+
+			activity: Default::default(),
+			mention_channels: Default::default(),
+			mention_everyone: rac.mention_everyone,
+			mention_roles: rac.role_mention_ids.iter().copied().map(RoleId).collect(),
+			mentions: rac
+				.user_mentions
+				.iter()
+				.map(|user| (UserId(user.id), user.into()))
+				.collect(),
+			stickers: Default::default(),
+			tts: Default::default(),
+			webhook_id: Default::default(),
+			pinned: Default::default(),
+
+			// TODO: support replies!
+			reference: Default::default(),
+			referenced_message: Default::default(),
+		}
+	}
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct ServerMessage {
+pub struct DirectMessage {
+	/// Always `"direct_message"`, to tell payloads apart without looking at the URL.
+	#[serde(skip_deserializing, default = "direct_message_type")]
+	pub event_type: &'static str,
+	/// Always `true`.
+	#[serde(skip_deserializing, default = "always_true")]
+	pub is_dm: bool,
 	pub id: u64,
-	pub server_id: u64,
 	pub channel_id: u64,
-	pub author: Member,
+	pub author: User,
+	/// Number of other users in the channel: 1 for regular DMs, more for group DMs.
+	#[serde(default = "default_recipient_count")]
+	pub recipient_count: usize,
+	/// Whether this is the `ACCORD_DM_OPT_IN_COMMAND`, with `ACCORD_DM_OPT_IN_REQUIRED=1`.
+	#[serde(default)]
+	pub opt_in: bool,
 
 	pub timestamp_created: String,
 	#[serde(default)]
@@ -452,7 +3234,31 @@ pub struct ServerMessage {
 
 	#[serde(default)]
 	pub kind: MessageType,
+	/// Deprecated alias of `content_raw`, which it is always equal to, kept for existing targets.
 	pub content: String,
+	/// The content exactly as sent, with Discord's mention syntax.
+	#[serde(default)]
+	pub content_raw: String,
+	/// The content with mentions resolved to names, when resolution was performed.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub content_resolved: Option<String>,
+	/// Number of whitespace-separated words in the content.
+	#[serde(default)]
+	pub word_count: usize,
+	/// Number of users mentioned.
+	#[serde(default)]
+	pub mention_count: usize,
+	/// Whether the message mentions `@everyone` or `@here`.
+	#[serde(default)]
+	pub mention_everyone: bool,
+	/// Users mentioned, ordered by ID.
+	#[serde(default)]
+	pub user_mentions: Vec<User>,
+	#[serde(default)]
+	pub role_mention_ids: Vec<u64>,
+	/// Hex digest of the content, with `ACCORD_CONTENT_HASH_ALGORITHM`.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub content_hash: Option<String>,
 
 	#[serde(default)]
 	pub attachments: Vec<Attachment>,
@@ -467,36 +3273,46 @@ pub struct ServerMessage {
 	pub flags: Vec<MessageFlag>,
 }
 
-impl Sendable for ServerMessage {
+fn default_recipient_count() -> usize {
+	1
+}
+
+fn direct_message_type() -> &'static str {
+	"direct_message"
+}
+
+fn always_true() -> bool {
+	true
+}
+
+impl Sendable for DirectMessage {
 	fn url(&self) -> String {
-		format!(
-			"/server/{}/channel/{}/message",
-			self.server_id, self.channel_id
-		)
+		format!("/direct/{}/message", self.channel_id)
+	}
+
+	fn channel_id(&self) -> Option<u64> {
+		Some(self.channel_id)
+	}
+
+	fn idempotency_key(&self) -> Option<String> {
+		Some(format!("msg-{}", self.id))
 	}
 
 	fn customise(&self, mut req: RequestBuilder) -> RequestBuilder {
 		req = req
 			.header("accord-message-id", self.id)
-			.header("accord-server-id", self.server_id)
-			.header("accord-channel-type", "text")
+			.header("accord-channel-type", "direct")
 			.header("accord-channel-id", self.channel_id)
 			.header(
 				"accord-author-type",
-				if self.author.user.bot { "bot" } else { "user" },
+				if self.author.bot { "bot" } else { "user" }.to_string(),
 			)
-			.header("accord-author-id", self.author.user.id)
-			.header("accord-author-name", &escape(&self.author.user.name))
+			.header("accord-author-id", self.author.id)
+			.header("accord-author-name", &escape(&self.author.name))
 			.header("accord-content-length", self.content.len());
 
-		if let Some(ref pseud) = self.author.pseudonym {
-			req = req.header("accord-author-pseudonym", escape(pseud));
-		}
-
-		if let Some(ref roles) = self.author.roles {
-			for role in roles {
-				req = req.header("accord-author-role-ids", *role);
-			}
+		if let Some(ref hash) = self.content_hash {
+			req = req.header("accord-content-hash", hash);
 		}
 
 		for flag in &self.flags {
@@ -519,152 +3335,555 @@ impl Sendable for ServerMessage {
 	}
 }
 
-impl From<&DisMessage> for ServerMessage {
-	/// Convert from a Discord message to a Raccord ServerMessage
-	///
+/// A channel's details, as it is created, updated, or deleted.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChannelEvent {
+	pub id: u64,
+	/// Absent for DM channels.
+	pub server_id: Option<u64>,
+	pub name: Option<String>,
+	pub kind: ChannelType,
+	/// Sorting position in the server's channel list, for server channels.
+	pub position: Option<u64>,
+}
+
+impl ChannelEvent {
+	fn url(&self, action: &str) -> String {
+		match self.server_id {
+			Some(server_id) => format!("/server/{}/channel/{}/{}", server_id, self.id, action),
+			None => format!("/direct/{}/{}", self.id, action),
+		}
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		let req = req.header("accord-channel-id", self.id);
+		match self.server_id {
+			Some(server_id) => req
+				.header("accord-channel-type", "server")
+				.header("accord-server-id", server_id),
+			None => req.header("accord-channel-type", "direct"),
+		}
+	}
+}
+
+impl From<&Channel> for ChannelEvent {
+	fn from(dis: &Channel) -> Self {
+		let (server_id, kind, position) = match dis {
+			Channel::Group(group) => (None, group.kind, None),
+			Channel::Private(dm) => (None, dm.kind, None),
+			Channel::Guild(GuildChannel::Category(c)) => (c.guild_id, c.kind, Some(c.position)),
+			Channel::Guild(GuildChannel::Text(c)) => (c.guild_id, c.kind, Some(c.position)),
+			Channel::Guild(GuildChannel::Voice(c)) => (c.guild_id, c.kind, Some(c.position)),
+		};
+
+		Self {
+			id: dis.id().0,
+			server_id: server_id.map(|id| id.0),
+			name: dis.name().map(String::from),
+			kind,
+			position: position.and_then(|p| u64::try_from(p).ok()),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChannelCreated(pub ChannelEvent);
+
+impl Sendable for ChannelCreated {
+	fn url(&self) -> String {
+		self.0.url("create")
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		self.0.server_id
+	}
+
+	fn channel_id(&self) -> Option<u64> {
+		Some(self.0.id)
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		self.0.customise(req)
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChannelUpdated(pub ChannelEvent);
+
+impl Sendable for ChannelUpdated {
+	fn url(&self) -> String {
+		self.0.url("update")
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		self.0.server_id
+	}
+
+	fn channel_id(&self) -> Option<u64> {
+		Some(self.0.id)
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		self.0.customise(req)
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChannelDeleted(pub ChannelEvent);
+
+impl Sendable for ChannelDeleted {
+	fn url(&self) -> String {
+		self.0.url("delete")
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		self.0.server_id
+	}
+
+	fn channel_id(&self) -> Option<u64> {
+		Some(self.0.id)
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		self.0.customise(req)
+	}
+}
+
+/// A DM channel being opened with the bot, usually just before the first message in it.
+#[derive(Clone, Debug, Serialize)]
+pub struct DmChannelCreate {
+	pub channel_id: u64,
+	pub recipient: User,
+}
+
+impl Sendable for DmChannelCreate {
+	fn url(&self) -> String {
+		format!("/direct/{}/open", self.channel_id)
+	}
+
+	fn channel_id(&self) -> Option<u64> {
+		Some(self.channel_id)
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		req.header("accord-channel-type", "direct")
+			.header("accord-channel-id", self.channel_id)
+			.header(
+				"accord-user-type",
+				if self.recipient.bot { "bot" } else { "user" },
+			)
+			.header("accord-user-id", self.recipient.id)
+			.header("accord-user-name", &escape(&self.recipient.name))
+	}
+}
+
+/// An edit to a guild message. Fields other than the IDs are only present if they changed.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerMessageUpdate {
+	/// Always `"server_message_update"`.
+	pub event_type: &'static str,
+	pub id: u64,
+	pub server_id: u64,
+	pub channel_id: u64,
+	pub author: Option<User>,
+	/// When the message was edited, or when the update was received for updates which only load
+	/// embeds, as Discord doesn't provide a time for those.
+	pub edited_timestamp: String,
+	pub content: Option<String>,
+	pub attachments: Option<Vec<Attachment>>,
+	pub embeds: Option<Vec<Embed>>,
+}
+
+fn edited_timestamp(dis: &MessageUpdate) -> String {
+	dis.edited_timestamp
+		.clone()
+		.unwrap_or_else(|| chrono::Utc::now().to_rfc3339())
+}
+
+impl From<&MessageUpdate> for ServerMessageUpdate {
 	/// # Panics
 	///
 	/// Will panic if there's no `guild_id`.
-	fn from(dis: &DisMessage) -> Self {
+	fn from(dis: &MessageUpdate) -> Self {
 		Self {
+			event_type: "server_message_update",
 			id: dis.id.0,
 			server_id: dis.guild_id.unwrap().0,
 			channel_id: dis.channel_id.0,
-			author: dis.into(),
+			author: dis.author.as_ref().map(User::from),
+			edited_timestamp: edited_timestamp(dis),
+			content: dis.content.clone(),
+			attachments: dis.attachments.clone(),
+			embeds: dis.embeds.clone(),
+		}
+	}
+}
 
-			timestamp_created: dis.timestamp.clone(),
-			timestamp_edited: dis.edited_timestamp.clone(),
+impl Sendable for ServerMessageUpdate {
+	fn url(&self) -> String {
+		format!(
+			"/server/{}/channel/{}/message/{}/update",
+			self.server_id, self.channel_id, self.id
+		)
+	}
 
-			kind: dis.kind.into(),
-			content: dis.content.clone(),
+	fn channel_id(&self) -> Option<u64> {
+		Some(self.channel_id)
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		Some(self.server_id)
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		req.header("accord-message-id", self.id)
+			.header("accord-server-id", self.server_id)
+			.header("accord-channel-type", "text")
+			.header("accord-channel-id", self.channel_id)
+	}
+}
 
+/// An edit to a direct message. Fields other than the IDs are only present if they changed.
+#[derive(Clone, Debug, Serialize)]
+pub struct DirectMessageUpdate {
+	/// Always `"direct_message_update"`.
+	pub event_type: &'static str,
+	pub id: u64,
+	pub channel_id: u64,
+	pub author: Option<User>,
+	/// When the message was edited, or when the update was received for updates which only load
+	/// embeds, as Discord doesn't provide a time for those.
+	pub edited_timestamp: String,
+	pub content: Option<String>,
+	pub attachments: Option<Vec<Attachment>>,
+	pub embeds: Option<Vec<Embed>>,
+}
+
+impl From<&MessageUpdate> for DirectMessageUpdate {
+	fn from(dis: &MessageUpdate) -> Self {
+		Self {
+			event_type: "direct_message_update",
+			id: dis.id.0,
+			channel_id: dis.channel_id.0,
+			author: dis.author.as_ref().map(User::from),
+			edited_timestamp: edited_timestamp(dis),
+			content: dis.content.clone(),
 			attachments: dis.attachments.clone(),
 			embeds: dis.embeds.clone(),
-			reactions: dis.reactions.clone(),
+		}
+	}
+}
 
-			application: dis.application.clone(),
-			flags: dis.flags.map(MessageFlag::from_discord).unwrap_or_default(),
+impl Sendable for DirectMessageUpdate {
+	fn url(&self) -> String {
+		format!("/direct/{}/message/{}/update", self.channel_id, self.id)
+	}
+
+	fn channel_id(&self) -> Option<u64> {
+		Some(self.channel_id)
+	}
+
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		req.header("accord-message-id", self.id)
+			.header("accord-channel-type", "direct")
+			.header("accord-channel-id", self.channel_id)
+	}
+}
+
+/// A deleted message. Bulk deletions are sent as one of these per message.
+#[derive(Clone, Debug, Serialize)]
+pub struct MessageDelete {
+	pub id: u64,
+	pub channel_id: u64,
+	pub server_id: Option<u64>,
+}
+
+impl Sendable for MessageDelete {
+	fn url(&self) -> String {
+		match self.server_id {
+			Some(server_id) => format!(
+				"/server/{}/channel/{}/message/{}/delete",
+				server_id, self.channel_id, self.id
+			),
+			None => format!("/direct/{}/message/{}/delete", self.channel_id, self.id),
+		}
+	}
+
+	fn channel_id(&self) -> Option<u64> {
+		Some(self.channel_id)
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		self.server_id
+	}
+
+	fn customise(&self, mut req: RequestBuilder) -> RequestBuilder {
+		req = req
+			.header("accord-message-id", self.id)
+			.header("accord-channel-id", self.channel_id);
+
+		if let Some(server_id) = self.server_id {
+			req.header("accord-server-id", server_id)
+				.header("accord-channel-type", "text")
+		} else {
+			req.header("accord-channel-type", "direct")
 		}
 	}
 }
 
-impl From<&ServerMessage> for DisMessage {
-	/// Convert from a Raccord ServerMessage to a Discord Message
-	fn from(rac: &ServerMessage) -> Self {
+/// An emoji as used in a reaction: either a custom emoji (with an ID) or a unicode one.
+#[derive(Clone, Debug, Serialize)]
+pub struct PartialEmoji {
+	pub id: Option<u64>,
+	/// Empty if a custom emoji has since been deleted.
+	pub name: String,
+}
+
+impl From<&ReactionType> for PartialEmoji {
+	fn from(dis: &ReactionType) -> Self {
+		match dis {
+			ReactionType::Custom { id, name, .. } => Self {
+				id: Some(id.0),
+				name: name.clone().unwrap_or_default(),
+			},
+			ReactionType::Unicode { name } => Self {
+				id: None,
+				name: name.clone(),
+			},
+		}
+	}
+}
+
+/// A reaction added to a message.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReactionAdd {
+	pub user_id: u64,
+	pub channel_id: u64,
+	pub message_id: u64,
+	pub server_id: Option<u64>,
+	pub emoji: PartialEmoji,
+}
+
+impl From<&Reaction> for ReactionAdd {
+	fn from(dis: &Reaction) -> Self {
 		Self {
-			id: MessageId(rac.id),
-			guild_id: Some(GuildId(rac.server_id)),
-			channel_id: ChannelId(rac.channel_id),
-			author: (&rac.author.user).into(),
-			member: Some((&rac.author).into()),
+			user_id: dis.user_id.0,
+			channel_id: dis.channel_id.0,
+			message_id: dis.message_id.0,
+			server_id: dis.guild_id.map(|id| id.0),
+			emoji: PartialEmoji::from(&dis.emoji),
+		}
+	}
+}
 
-			timestamp: rac.timestamp_created.clone(),
-			edited_timestamp: rac.timestamp_edited.clone(),
+impl Sendable for ReactionAdd {
+	fn url(&self) -> String {
+		reaction_url(self.server_id, self.channel_id, self.message_id, "add")
+	}
 
-			kind: rac.kind.into(),
-			content: rac.content.clone(),
+	fn channel_id(&self) -> Option<u64> {
+		Some(self.channel_id)
+	}
 
-			attachments: rac.attachments.clone(),
-			embeds: rac.embeds.clone(),
-			reactions: rac.reactions.clone(),
+	fn server_id(&self) -> Option<u64> {
+		self.server_id
+	}
 
-			application: rac.application.clone(),
-			flags: MessageFlag::to_discord(&rac.flags),
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		reaction_headers(
+			req,
+			self.server_id,
+			self.channel_id,
+			self.message_id,
+			self.user_id,
+		)
+	}
+}
 
-			activity: Default::default(),
-			mention_channels: Default::default(),
-			mention_everyone: Default::default(),
-			mention_roles: Default::default(),
-			mentions: Default::default(),
-			stickers: Default::default(),
-			tts: Default::default(),
-			webhook_id: Default::default(),
-			pinned: Default::default(),
+/// A reaction removed from a message.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReactionRemove {
+	pub user_id: u64,
+	pub channel_id: u64,
+	pub message_id: u64,
+	pub server_id: Option<u64>,
+	pub emoji: PartialEmoji,
+}
 
-			// TODO: support replies!
-			reference: Default::default(),
-			referenced_message: Default::default(),
+impl From<&Reaction> for ReactionRemove {
+	fn from(dis: &Reaction) -> Self {
+		Self {
+			user_id: dis.user_id.0,
+			channel_id: dis.channel_id.0,
+			message_id: dis.message_id.0,
+			server_id: dis.guild_id.map(|id| id.0),
+			emoji: PartialEmoji::from(&dis.emoji),
 		}
 	}
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct DirectMessage {
-	pub id: u64,
-	pub channel_id: u64,
-	pub author: User,
+impl Sendable for ReactionRemove {
+	fn url(&self) -> String {
+		reaction_url(self.server_id, self.channel_id, self.message_id, "remove")
+	}
 
-	pub timestamp_created: String,
-	#[serde(default)]
-	pub timestamp_edited: Option<String>,
+	fn channel_id(&self) -> Option<u64> {
+		Some(self.channel_id)
+	}
 
-	#[serde(default)]
-	pub kind: MessageType,
-	pub content: String,
+	fn server_id(&self) -> Option<u64> {
+		self.server_id
+	}
 
-	#[serde(default)]
-	pub attachments: Vec<Attachment>,
-	#[serde(default)]
-	pub embeds: Vec<Embed>,
-	#[serde(default)]
-	pub reactions: Vec<MessageReaction>,
+	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
+		reaction_headers(
+			req,
+			self.server_id,
+			self.channel_id,
+			self.message_id,
+			self.user_id,
+		)
+	}
+}
 
-	#[serde(default)]
-	pub application: Option<MessageApplication>,
-	#[serde(default)]
-	pub flags: Vec<MessageFlag>,
+/// Someone started typing in a channel, with `ACCORD_FORWARD_TYPING`.
+#[derive(Clone, Debug, Serialize)]
+pub struct TypingStart {
+	pub channel_id: u64,
+	pub server_id: Option<u64>,
+	pub user_id: u64,
+	/// Unix timestamp, in seconds.
+	pub timestamp: u64,
 }
 
-impl Sendable for DirectMessage {
+impl From<&DisTypingStart> for TypingStart {
+	fn from(dis: &DisTypingStart) -> Self {
+		Self {
+			channel_id: dis.channel_id.0,
+			server_id: dis.guild_id.map(|id| id.0),
+			user_id: dis.user_id.0,
+			timestamp: dis.timestamp,
+		}
+	}
+}
+
+impl Sendable for TypingStart {
 	fn url(&self) -> String {
-		format!("/direct/{}/message", self.channel_id)
+		match self.server_id {
+			Some(server_id) => format!("/server/{}/channel/{}/typing", server_id, self.channel_id),
+			None => format!("/direct/{}/typing", self.channel_id),
+		}
+	}
+
+	fn channel_id(&self) -> Option<u64> {
+		Some(self.channel_id)
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		self.server_id
 	}
 
 	fn customise(&self, mut req: RequestBuilder) -> RequestBuilder {
 		req = req
-			.header("accord-message-id", self.id)
-			.header("accord-channel-type", "direct")
 			.header("accord-channel-id", self.channel_id)
-			.header(
-				"accord-author-type",
-				if self.author.bot { "bot" } else { "user" }.to_string(),
-			)
-			.header("accord-author-id", self.author.id)
-			.header("accord-author-name", &escape(&self.author.name))
-			.header("accord-content-length", self.content.len());
+			.header("accord-user-id", self.user_id);
 
-		for flag in &self.flags {
-			req = req.header("accord-message-flags", flag.to_string());
+		if let Some(server_id) = self.server_id {
+			req.header("accord-server-id", server_id)
+				.header("accord-channel-type", "text")
+		} else {
+			req.header("accord-channel-type", "direct")
 		}
+	}
+}
 
-		if !self.attachments.is_empty() {
-			req = req.header("accord-has-attachments", self.attachments.len());
+/// Records the outcome of a post in the Prometheus metrics.
+#[cfg(feature = "metrics")]
+fn measure<'c>(
+	event_type: &'static str,
+	res: Result<TargetResponse<'c>, Error>,
+) -> Result<TargetResponse<'c>, Error> {
+	let started = Instant::now();
+	match res {
+		Err(err) => {
+			crate::metrics::record(event_type, false, started.elapsed());
+			Err(err)
 		}
-
-		if !self.embeds.is_empty() {
-			req = req.header("accord-has-embeds", self.embeds.len());
+		Ok(fut) => Ok(async move {
+			let res = fut.await;
+			let ok = res.as_ref().is_ok_and(|r| !r.status().is_server_error());
+			crate::metrics::record(event_type, ok, started.elapsed());
+			res
 		}
+		.boxed()),
+	}
+}
 
-		if !self.reactions.is_empty() {
-			req = req.header("accord-has-reactions", self.reactions.len());
-		}
+#[cfg(not(feature = "metrics"))]
+fn measure<'c>(
+	_event_type: &'static str,
+	res: Result<TargetResponse<'c>, Error>,
+) -> Result<TargetResponse<'c>, Error> {
+	res
+}
 
-		req
+fn reaction_url(server_id: Option<u64>, channel_id: u64, message_id: u64, action: &str) -> String {
+	match server_id {
+		Some(server_id) => format!(
+			"/server/{}/channel/{}/message/{}/reaction/{}",
+			server_id, channel_id, message_id, action
+		),
+		None => format!(
+			"/direct/{}/message/{}/reaction/{}",
+			channel_id, message_id, action
+		),
+	}
+}
+
+fn reaction_headers(
+	mut req: RequestBuilder,
+	server_id: Option<u64>,
+	channel_id: u64,
+	message_id: u64,
+	user_id: u64,
+) -> RequestBuilder {
+	req = req
+		.header("accord-message-id", message_id)
+		.header("accord-channel-id", channel_id)
+		.header("accord-user-id", user_id);
+
+	if let Some(server_id) = server_id {
+		req.header("accord-server-id", server_id)
+			.header("accord-channel-type", "text")
+	} else {
+		req.header("accord-channel-type", "direct")
 	}
 }
 
 impl From<&DisMessage> for DirectMessage {
 	fn from(dis: &DisMessage) -> Self {
 		Self {
+			event_type: direct_message_type(),
+			is_dm: true,
 			id: dis.id.0,
 			channel_id: dis.channel_id.0,
 			author: User::from(&dis.author),
+			recipient_count: default_recipient_count(),
+			opt_in: false,
 
 			timestamp_created: dis.timestamp.clone(),
 			timestamp_edited: dis.edited_timestamp.clone(),
 
 			kind: dis.kind.into(),
 			content: dis.content.clone(),
+			content_raw: dis.content.clone(),
+			content_resolved: None,
+			word_count: dis.content.split_whitespace().count(),
+			mention_count: dis.mentions.len(),
+			mention_everyone: dis.mention_everyone,
+			user_mentions: user_mentions(dis),
+			role_mention_ids: dis.mention_roles.iter().map(|id| id.0).collect(),
+			content_hash: None,
 
 			attachments: dis.attachments.clone(),
 			embeds: dis.embeds.clone(),
@@ -701,9 +3920,13 @@ impl From<&DirectMessage> for DisMessage {
 
 			activity: Default::default(),
 			mention_channels: Default::default(),
-			mention_everyone: Default::default(),
-			mention_roles: Default::default(),
-			mentions: Default::default(),
+			mention_everyone: rac.mention_everyone,
+			mention_roles: rac.role_mention_ids.iter().copied().map(RoleId).collect(),
+			mentions: rac
+				.user_mentions
+				.iter()
+				.map(|user| (UserId(user.id), user.into()))
+				.collect(),
 			stickers: Default::default(),
 			tts: Default::default(),
 			webhook_id: Default::default(),
@@ -716,9 +3939,32 @@ impl From<&DirectMessage> for DisMessage {
 	}
 }
 
+/// The result of matching a message against the command regexes.
+#[derive(Clone, Debug)]
+pub struct CommandMatch {
+	pub command: Vec<String>,
+	pub raw_command: String,
+	pub confidence: f32,
+}
+
+impl CommandMatch {
+	pub fn into_command<M: Sendable>(self, message: M) -> Command<M> {
+		Command {
+			command: self.command,
+			raw_command: self.raw_command,
+			confidence: self.confidence,
+			message,
+		}
+	}
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub struct Command<M: Sendable> {
 	pub command: Vec<String>,
+	/// The part of the message content matched by the match regex.
+	pub raw_command: String,
+	/// How much of the message content the match regex covered, from 0.0 to 1.0.
+	pub confidence: f32,
 	pub message: M,
 }
 
@@ -730,8 +3976,654 @@ impl<S: Sendable> Sendable for Command<S> {
 	fn customise(&self, req: RequestBuilder) -> RequestBuilder {
 		self.message.customise(req)
 	}
+
+	fn idempotency_key(&self) -> Option<String> {
+		self.message.idempotency_key()
+	}
+
+	fn server_id(&self) -> Option<u64> {
+		self.message.server_id()
+	}
+
+	fn channel_id(&self) -> Option<u64> {
+		self.message.channel_id()
+	}
+}
+
+/// Connections to the target are pooled and reused by the underlying curl
+/// connection cache, which `ACCORD_TARGET_POOL_MAX_IDLE` sizes.
+fn http_builder() -> HttpClientBuilder {
+	HttpClient::builder()
+		.default_header("accord-version", env!("CARGO_PKG_VERSION"))
+		.redirect_policy(RedirectPolicy::Limit(8))
+		.auto_referer()
+		.tcp_keepalive(Duration::from_secs(15))
+		.tcp_nodelay()
+		.connect_timeout(Duration::from_secs(5))
+}
+
+/// Normalises a payload type name so that e.g. `server_message` matches `ServerMessage`.
+fn event_type_key(name: &str) -> String {
+	name.trim().replace('_', "").to_lowercase()
+}
+
+/// Matches Discord's user (`<@id>` or `<@!id>`), role (`<@&id>`), and channel (`<#id>`) mentions.
+fn mention_regex() -> &'static Regex {
+	static MENTION: OnceLock<Regex> = OnceLock::new();
+	MENTION.get_or_init(|| Regex::new(r"<(@!?|@&|#)(\d+)>").unwrap())
+}
+
+/// Recompiles a command regex to ignore case, for `ACCORD_COMMAND_CASE_INSENSITIVE`.
+fn case_insensitive(regex: &Regex) -> Regex {
+	RegexBuilder::new(regex.as_str())
+		.case_insensitive(true)
+		.build()
+		.expect("FATAL: bad regex: ACCORD_COMMAND_MATCH or ACCORD_COMMAND_PARSE")
 }
 
 fn escape(s: &str) -> String {
 	s.escape_unicode().to_string()
 }
+
+/// Parses a Discord channel type, either as its integer value or its name.
+///
+/// Only types which the Discord API version in use can report have names: threads (types 11 and
+/// 12) aren't supported by twilight yet.
+fn channel_type(kind: &str) -> Option<u8> {
+	if let Ok(n) = kind.parse() {
+		return Some(n);
+	}
+
+	Some(match kind.to_lowercase().replace('-', "_").as_str() {
+		"text" => 0,
+		"dm" | "private" => 1,
+		"voice" => 2,
+		"group" | "group_dm" => 3,
+		"category" => 4,
+		"news" | "announcement" => 5,
+		"store" => 6,
+		_ => return None,
+	})
+}
+
+/// Removes null-valued fields from objects, recursively.
+///
+/// Nulls inside arrays are kept, as removing them would shift indices.
+fn strip_nulls(value: &mut Value) {
+	match value {
+		Value::Object(map) => {
+			*map = std::mem::take(map)
+				.into_iter()
+				.filter(|(_, v)| !v.is_null())
+				.map(|(k, mut v)| {
+					strip_nulls(&mut v);
+					(k, v)
+				})
+				.collect();
+		}
+		Value::Array(vec) => vec.iter_mut().for_each(strip_nulls),
+		_ => {}
+	}
+}
+
+/// Cuts down every `embeds` array for `ACCORD_EMBED_FORWARDING`, recursively: removes them for
+/// `none`, or keeps only each embed's `url` and `type` for `urls`. Not called for `full`.
+fn trim_embeds(value: &mut Value, mode: EmbedForwarding) {
+	match value {
+		Value::Object(map) => {
+			if mode == EmbedForwarding::None {
+				map.remove("embeds");
+			} else if let Some(Value::Array(embeds)) = map.get_mut("embeds") {
+				for embed in embeds.iter_mut() {
+					if let Value::Object(fields) = embed {
+						*fields = std::mem::take(fields)
+							.into_iter()
+							.filter(|(key, _)| key == "url" || key == "type")
+							.collect();
+					}
+				}
+			}
+
+			map.values_mut().for_each(|v| trim_embeds(v, mode));
+		}
+		Value::Array(vec) => vec.iter_mut().for_each(|v| trim_embeds(v, mode)),
+		_ => {}
+	}
+}
+
+/// Replaces every `embeds` array with an `embed_urls` array of the embeds' URLs, recursively.
+fn embeds_to_urls(value: &mut Value) {
+	match value {
+		Value::Object(map) => {
+			if let Some(Value::Array(embeds)) = map.remove("embeds") {
+				let urls = embeds
+					.into_iter()
+					.filter_map(|mut embed| embed.get_mut("url").map(Value::take))
+					.filter(Value::is_string)
+					.collect();
+				map.insert("embed_urls".into(), Value::Array(urls));
+			}
+
+			map.values_mut().for_each(embeds_to_urls);
+		}
+		Value::Array(vec) => vec.iter_mut().for_each(embeds_to_urls),
+		_ => {}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn server_message() -> ServerMessage {
+		serde_json::from_value(serde_json::json!({
+			"id": 3,
+			"server_id": 1,
+			"channel_id": 2,
+			"author": { "user": { "id": 4, "name": "someone" }, "server_id": 1 },
+			"timestamp_created": "2020-01-01T00:00:00+00:00",
+			"content": "hello world",
+		}))
+		.unwrap()
+	}
+
+	fn cache_channel(cache: &InMemoryCache, id: u64, name: &str) {
+		let channel: Channel = serde_json::from_value(serde_json::json!({
+			"id": id.to_string(),
+			"type": 0,
+			"guild_id": "1",
+			"name": name,
+			"position": 0,
+			"permission_overwrites": [],
+		}))
+		.unwrap();
+		cache.update(&twilight_model::gateway::payload::ChannelCreate(channel));
+	}
+
+	#[test]
+	fn channel_allow_regex() {
+		let mut client = Client::new(String::new(), None, None);
+		client.channel_allow_regex = Some(Regex::new("^#?(general|support)$").unwrap());
+
+		let cache = InMemoryCache::new();
+		cache_channel(&cache, 10, "general");
+		cache_channel(&cache, 11, "#support");
+		cache_channel(&cache, 12, "general-chat");
+		cache_channel(&cache, 13, "off-topic");
+
+		let guild = Some(GuildId(1));
+		assert!(client.wants_channel(guild, ChannelId(10), &cache));
+		assert!(client.wants_channel(guild, ChannelId(11), &cache));
+		assert!(!client.wants_channel(guild, ChannelId(12), &cache));
+		assert!(!client.wants_channel(guild, ChannelId(13), &cache));
+		assert!(
+			!client.wants_channel(guild, ChannelId(14), &cache),
+			"uncached channels have no name to match"
+		);
+		assert!(
+			client.wants_channel(None, ChannelId(14), &cache),
+			"DMs aren't filtered by name"
+		);
+	}
+
+	#[test]
+	fn resolve_mentions_from_message_and_cache() {
+		let mut client = Client::new(String::new(), None, None);
+		let cache = InMemoryCache::new();
+		cache_channel(&cache, 10, "general");
+		let message: DisMessage = serde_json::from_value(serde_json::json!({
+			"id": "3",
+			"channel_id": "2",
+			"author": { "id": "4", "username": "someone", "discriminator": "0001", "avatar": null },
+			"content": "<@5> and <@!6>, see <#10> and <#11>",
+			"timestamp": "2020-01-01T00:00:00+00:00",
+			"tts": false,
+			"mention_everyone": false,
+			"mentions": [{ "id": "5", "username": "five", "discriminator": "0005", "avatar": null }],
+			"mention_roles": [],
+			"attachments": [],
+			"embeds": [],
+			"pinned": false,
+			"type": 0,
+		}))
+		.unwrap();
+
+		let http = DisHttpClient::new("token");
+		let resolve = |client: &Client| {
+			async_std::task::block_on(client.resolve_mentions(&message, &cache, &http))
+		};
+		assert_eq!(resolve(&client), None);
+
+		client.resolve_mentions = true;
+		assert_eq!(
+			resolve(&client).unwrap(),
+			"@five and <@!6>, see #general and <#11>"
+		);
+	}
+
+	#[test]
+	fn parse_command_with_regexes() {
+		let client = Client::new(
+			String::new(),
+			Some(String::from("^!\\w+")),
+			Some(String::from("(\\w+)")),
+		);
+
+		let command = client.parse_command("!roll 2 dice").unwrap();
+		assert_eq!(command.command, vec!["roll", "2", "dice"]);
+		assert_eq!(command.raw_command, "!roll");
+		assert!((command.confidence - 5.0 / 12.0).abs() < f32::EPSILON);
+
+		assert!(client.parse_command("roll 2 dice").is_none());
+	}
+
+	#[test]
+	fn parse_command_with_prefix() {
+		let mut client = Client::new(String::new(), None, None);
+		assert!(client.parse_command("!roll 2 dice").is_none());
+
+		client.command_prefix = Some(String::from("!"));
+		let command = client.parse_command("  !roll 2  dice ").unwrap();
+		assert_eq!(command.command, vec!["roll", "2", "dice"]);
+		assert_eq!(command.raw_command, "!roll 2  dice");
+		assert_eq!(command.confidence, 1.0);
+
+		assert!(client.parse_command("roll 2 dice").is_none());
+		assert!(client.parse_command("! roll").is_none());
+		assert!(client.parse_command("").is_none());
+	}
+
+	#[test]
+	fn parse_command_case_insensitive() {
+		let mut client = Client::new(
+			String::new(),
+			Some(String::from("^!roll")),
+			Some(String::from("(\\w+)")),
+		);
+		client.ignore_command_case();
+
+		let command = client.parse_command("!ROLL Two Dice").unwrap();
+		assert_eq!(command.command, vec!["roll", "Two", "Dice"]);
+		assert_eq!(command.raw_command, "!ROLL");
+
+		let mut client = Client::new(String::new(), None, None);
+		client.command_prefix = Some(String::from("ab!"));
+		client.ignore_command_case();
+		let command = client.parse_command("AB!Roll Two").unwrap();
+		assert_eq!(command.command, vec!["roll", "Two"]);
+		assert_eq!(command.raw_command, "AB!Roll Two");
+	}
+
+	#[test]
+	fn circuit_breaker_opens_and_recovers() {
+		let breaker = CircuitBreaker::new(2, Duration::from_millis(20));
+		assert!(breaker.allow());
+
+		breaker.record(false);
+		assert_eq!(breaker.state(), CircuitState::Closed);
+		breaker.record(false);
+		assert_eq!(breaker.state(), CircuitState::Open);
+		assert!(!breaker.allow());
+
+		std::thread::sleep(Duration::from_millis(30));
+		assert!(breaker.allow());
+		assert_eq!(breaker.state(), CircuitState::HalfOpen);
+		assert!(!breaker.allow(), "only one probe is let through");
+
+		breaker.record(false);
+		assert_eq!(breaker.state(), CircuitState::Open);
+
+		std::thread::sleep(Duration::from_millis(30));
+		assert!(breaker.allow());
+		breaker.record(true);
+		assert_eq!(breaker.state(), CircuitState::Closed);
+		assert!(breaker.allow());
+	}
+
+	#[test]
+	fn circuit_breaker_reprobes_after_lost_probe() {
+		let breaker = CircuitBreaker::new(1, Duration::from_millis(20));
+		breaker.record(false);
+		assert_eq!(breaker.state(), CircuitState::Open);
+
+		std::thread::sleep(Duration::from_millis(30));
+		assert!(breaker.allow());
+		// the probe is dropped without recording anything
+		assert!(!breaker.allow());
+
+		std::thread::sleep(Duration::from_millis(30));
+		assert!(breaker.allow(), "another probe is let through");
+		assert_eq!(breaker.state(), CircuitState::HalfOpen);
+		assert!(!breaker.allow());
+
+		breaker.record(true);
+		assert_eq!(breaker.state(), CircuitState::Closed);
+	}
+
+	#[test]
+	fn circuit_breaker_disabled() {
+		let breaker = CircuitBreaker::new(0, Duration::from_secs(30));
+		for _ in 0..10 {
+			breaker.record(false);
+		}
+
+		assert_eq!(breaker.state(), CircuitState::Closed);
+		assert!(breaker.allow());
+	}
+
+	#[test]
+	fn health_status_rejects_auth_errors() {
+		let status = |status_code| HealthStatus {
+			reachable: true,
+			latency_ms: 0,
+			status_code,
+		};
+		assert!(status(200).is_healthy());
+		assert!(status(404).is_healthy(), "a target without /health is up");
+		assert!(!status(401).is_healthy());
+		assert!(!status(403).is_healthy());
+		assert!(!status(503).is_healthy());
+	}
+
+	#[test]
+	fn health_check_times_out() {
+		// never accepted, so the request hangs until it times out
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let mut client = Client::new(
+			format!("http://{}", listener.local_addr().unwrap()),
+			None,
+			None,
+		);
+		client.request_timeout = Duration::from_millis(100);
+		client.breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+		client.auth.push(BearerTokenAuth {
+			token: String::from("abc"),
+		});
+
+		let req = client.health_request().unwrap();
+		assert_eq!(req.headers()["authorization"], "Bearer abc");
+
+		let status = async_std::task::block_on(client.health_check());
+		assert!(!status.reachable);
+		assert_eq!(client.breaker.state(), CircuitState::Open);
+	}
+
+	#[test]
+	fn retry_budget_runs_out() {
+		let budget = RetryBudget::new(2);
+		assert!(budget.take());
+		assert!(budget.take());
+		assert!(!budget.take());
+
+		let unlimited = RetryBudget::new(0);
+		assert!((0..100).all(|_| unlimited.take()));
+	}
+
+	#[test]
+	fn rate_limiter_allows_burst_then_waits() {
+		let limiter = RateLimiter::new(5.0, 2.0);
+		let start = Instant::now();
+		async_std::task::block_on(async {
+			limiter.acquire().await;
+			limiter.acquire().await;
+		});
+		assert!(start.elapsed() < Duration::from_millis(150));
+
+		async_std::task::block_on(limiter.acquire());
+		assert!(start.elapsed() >= Duration::from_millis(150));
+	}
+
+	#[test]
+	fn strip_nulls_recurses_into_objects() {
+		let mut value = serde_json::json!({
+			"a": null,
+			"b": { "c": null, "d": 1 },
+			"e": [null, { "f": null }],
+		});
+		strip_nulls(&mut value);
+		assert_eq!(
+			value,
+			serde_json::json!({ "b": { "d": 1 }, "e": [null, {}] })
+		);
+	}
+
+	#[test]
+	fn trim_embeds_modes() {
+		let message = serde_json::json!({
+			"content": "look",
+			"embeds": [{ "type": "link", "url": "https://example.com", "title": "Example" }],
+			"referenced": { "embeds": [{ "type": "image", "description": "a cat" }] },
+		});
+
+		let mut urls = message.clone();
+		trim_embeds(&mut urls, EmbedForwarding::Urls);
+		assert_eq!(
+			urls,
+			serde_json::json!({
+				"content": "look",
+				"embeds": [{ "type": "link", "url": "https://example.com" }],
+				"referenced": { "embeds": [{ "type": "image" }] },
+			})
+		);
+
+		let mut none = message;
+		trim_embeds(&mut none, EmbedForwarding::None);
+		assert_eq!(
+			none,
+			serde_json::json!({ "content": "look", "referenced": {} })
+		);
+	}
+
+	#[test]
+	fn embeds_to_urls_keeps_only_urls() {
+		let mut value = serde_json::json!({
+			"embeds": [
+				{ "type": "link", "url": "https://example.com" },
+				{ "type": "rich", "title": "no url" },
+			],
+			"referenced": { "embeds": [] },
+		});
+		embeds_to_urls(&mut value);
+		assert_eq!(
+			value,
+			serde_json::json!({
+				"embed_urls": ["https://example.com"],
+				"referenced": { "embed_urls": [] },
+			})
+		);
+	}
+
+	#[test]
+	fn guild_features_header_from_cache() {
+		let mut client = Client::new(String::new(), None, None);
+		client.guild_features_header = Some(String::from("x-features"));
+		let features = |client: &Client| {
+			let req = client.prepare(Request::post("http://localhost/"), &server_message());
+			let req = req.body(()).unwrap();
+			req.headers().get("x-features").cloned()
+		};
+		assert_eq!(features(&client), None, "no cache to read from");
+
+		let cache = InMemoryCache::new();
+		let guild: Guild = serde_json::from_value(serde_json::json!({
+			"id": "1",
+			"name": "server",
+			"afk_timeout": 300,
+			"default_message_notifications": 0,
+			"explicit_content_filter": 0,
+			"features": ["COMMUNITY", "NEWS"],
+			"mfa_level": 0,
+			"owner_id": "4",
+			"preferred_locale": "en-US",
+			"premium_tier": 0,
+			"region": "europe",
+			"system_channel_flags": 0,
+			"verification_level": 0,
+			"emojis": [],
+			"roles": [],
+		}))
+		.unwrap();
+		cache.update(&twilight_model::gateway::payload::GuildCreate(guild));
+		client.set_cache(cache);
+		assert_eq!(features(&client).unwrap(), "COMMUNITY,NEWS");
+	}
+
+	#[test]
+	fn auth_chain_parses_strategies() {
+		let chain: AuthChain = r#"[
+			{ "type": "hmac_sha256", "secret": "hunter2" },
+			{ "type": "bearer", "token": "abc" },
+			{ "type": "api_key", "key": "def", "header": "x-key" },
+			{ "type": "basic", "username": "user", "password": "pass" },
+			{ "type": "none" }
+		]"#
+		.parse()
+		.unwrap();
+		assert_eq!(chain.0.len(), 5);
+
+		let req = chain
+			.apply(Request::post("http://localhost/"), b"{}")
+			.body(())
+			.unwrap();
+		let headers = req.headers();
+		assert!(headers.contains_key("accord-signature"));
+		assert_eq!(headers["authorization"], "Bearer abc");
+		assert_eq!(headers["x-key"], "def");
+	}
+
+	#[test]
+	fn auth_chain_rejects_unknown_strategies() {
+		assert!("[{ \"type\": \"oauth\" }]".parse::<AuthChain>().is_err());
+		assert!("[{ \"type\": \"bearer\" }]".parse::<AuthChain>().is_err());
+	}
+
+	#[test]
+	fn batching_buffers_keyed_payloads() {
+		let mut client = Client::new(String::new(), None, None);
+		client.batch_max = 3;
+
+		for _ in 0..2 {
+			let res = async_std::task::block_on(client.post(server_message()).unwrap()).unwrap();
+			assert_eq!(res.status(), 204);
+		}
+
+		let batch = client.batch.lock().unwrap();
+		assert_eq!(batch.len(), 2);
+		assert_eq!(batch[0].key.as_deref(), Some("1-2"));
+		assert_eq!(batch[0].value["content"], "hello world");
+		assert_eq!(batch[0].value["v"], client.payload_version);
+	}
+
+	#[test]
+	fn batch_event_types_override_keys() {
+		let mut client = Client::new(String::new(), None, None);
+		client.batch_max = 3;
+		assert!(client.batches(&server_message()));
+		assert!(!client.batches(&ShardCount {
+			path: String::from("/shards"),
+			shard_count: 1,
+		}));
+
+		client.batch_event_types = Some(
+			["shard_count", " direct_message"]
+				.iter()
+				.map(|kind| event_type_key(kind))
+				.collect(),
+		);
+		assert!(!client.batches(&server_message()));
+		assert!(client.batches(&ShardCount {
+			path: String::from("/shards"),
+			shard_count: 1,
+		}));
+	}
+
+	#[test]
+	fn dead_letters_are_written_before_errors_resolve() {
+		let path =
+			env::temp_dir().join(format!("accord-dead-letters-{}.ndjson", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+
+		let mut client = Client::new(String::from("http://localhost"), None, None);
+		client.dead_letters = Some(Arc::new(DeadLetters::open(path.to_str().unwrap()).unwrap()));
+		client.draining.store(true, Ordering::SeqCst);
+
+		let res = async_std::task::block_on(client.post(server_message()).unwrap());
+		assert!(matches!(res, Err(Error::Draining)));
+
+		let written = std::fs::read_to_string(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		let letter: Value = serde_json::from_str(written.lines().next().unwrap()).unwrap();
+		assert_eq!(letter["url"], "http://localhost/server/1/channel/2/message");
+		assert_eq!(letter["payload"]["content"], "hello world");
+	}
+
+	#[test]
+	fn event_type_ignores_generics() {
+		let message = server_message();
+		assert_eq!(message.event_type(), "ServerMessage");
+
+		let command = CommandMatch {
+			command: vec![String::from("roll")],
+			raw_command: String::from("!roll"),
+			confidence: 1.0,
+		};
+		assert_eq!(command.into_command(message).event_type(), "Command");
+	}
+
+	#[test]
+	fn hmac_signs_sent_body() {
+		use std::io::{BufRead, BufReader, Read, Write};
+
+		// accepts one request, and returns its signature header and body
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let base = format!("http://{}", listener.local_addr().unwrap());
+		let target = std::thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			let mut reader = BufReader::new(stream);
+			let (mut signature, mut length) = (String::new(), 0);
+			loop {
+				let mut line = String::new();
+				reader.read_line(&mut line).unwrap();
+				let line = line.trim_end();
+				if line.is_empty() {
+					break;
+				}
+
+				if let Some((name, value)) = line.split_once(':') {
+					match name.to_lowercase().as_str() {
+						"accord-signature" => signature = value.trim().to_string(),
+						"content-length" => length = value.trim().parse().unwrap(),
+						_ => {}
+					}
+				}
+			}
+
+			let mut body = vec![0; length];
+			reader.read_exact(&mut body).unwrap();
+			reader
+				.into_inner()
+				.write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n")
+				.unwrap();
+			(signature, body)
+		});
+
+		let mut client = Client::new(base, None, None);
+		client.serialize_nulls = false;
+		client.auth.push(HmacSha256Auth {
+			secret: String::from("hunter2"),
+			header: signature_header(),
+		});
+		async_std::task::block_on(async { client.post(server_message()).unwrap().await }).unwrap();
+
+		let (signature, body) = target.join().unwrap();
+		assert_ne!(
+			body,
+			serde_json::to_vec(&server_message()).unwrap(),
+			"the sent body is transformed from the payload"
+		);
+
+		let mut mac = Hmac::<Sha256>::new_varkey(b"hunter2").unwrap();
+		mac.update(&body);
+		assert_eq!(signature, format!("{:x}", mac.finalize().into_bytes()));
+	}
+}
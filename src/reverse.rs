@@ -1,16 +1,16 @@
-use crate::raccord::{DirectMessage, ServerMessage};
+use crate::{
+	error::Error,
+	raccord::{DirectMessage, ServerMessage},
+};
 use async_channel::Sender;
-use std::{error::Error, fmt::Debug};
+use std::fmt::Debug;
 use tide::Server;
 use tide::{Request, Response, StatusCode};
 use tide_tracing::TraceMiddleware;
 use twilight_gateway::Event;
 use twilight_model::{channel::Message, gateway::payload::MessageCreate};
 
-pub async fn server(
-	bind: String,
-	ghosts: Sender<(u64, Event)>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+pub async fn server(bind: String, ghosts: Sender<(u64, Event)>) -> Result<(), Error> {
 	#[derive(Clone, Debug)]
 	struct State {
 		pub ghosts: Sender<(u64, Event)>,
@@ -0,0 +1,86 @@
+use std::{
+	io::{BufRead, BufReader, Read, Write},
+	net::TcpListener,
+	sync::Arc,
+	thread,
+};
+
+use accord::{cache::LruInMemoryCache, forward::try_event, raccord};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_gateway::Event;
+use twilight_http::Client as HttpClient;
+use twilight_model::gateway::payload::BanAdd;
+
+/// Accepts a single request, answers it with a 204, and returns its request line and body.
+fn mock_target() -> (String, thread::JoinHandle<(String, String)>) {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let base = format!("http://{}", listener.local_addr().unwrap());
+
+	let handle = thread::spawn(move || {
+		let (stream, _) = listener.accept().unwrap();
+		let mut reader = BufReader::new(stream);
+
+		let mut request_line = String::new();
+		reader.read_line(&mut request_line).unwrap();
+
+		let mut length = 0;
+		loop {
+			let mut header = String::new();
+			reader.read_line(&mut header).unwrap();
+			let header = header.trim_end();
+			if header.is_empty() {
+				break;
+			}
+
+			if let Some((name, value)) = header.split_once(':') {
+				if name.eq_ignore_ascii_case("content-length") {
+					length = value.trim().parse().unwrap();
+				}
+			}
+		}
+
+		let mut body = vec![0; length];
+		reader.read_exact(&mut body).unwrap();
+		reader
+			.into_inner()
+			.write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+			.unwrap();
+
+		(
+			request_line.trim_end().to_string(),
+			String::from_utf8(body).unwrap(),
+		)
+	});
+
+	(base, handle)
+}
+
+#[async_std::test]
+async fn ban_add_is_forwarded() {
+	let (base, server) = mock_target();
+	let target = Arc::new(raccord::Client::new(base, None, None));
+	let cache = LruInMemoryCache::new(InMemoryCache::new(), None);
+	let (player, _stage) = async_channel::unbounded();
+
+	let ban: BanAdd = serde_json::from_value(serde_json::json!({
+		"guild_id": "123",
+		"user": {
+			"id": "456",
+			"username": "someone",
+			"discriminator": "0001",
+			"avatar": null,
+		},
+	}))
+	.unwrap();
+	let http = HttpClient::new("token");
+	try_event(cache, target, http, 0, Event::BanAdd(ban), player)
+		.await
+		.unwrap();
+
+	let (request_line, body) = server.join().unwrap();
+	assert_eq!(request_line, "POST /server/123/ban/add HTTP/1.1");
+
+	let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+	assert_eq!(body["server_id"], 123);
+	assert_eq!(body["user"]["id"], 456);
+}